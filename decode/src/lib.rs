@@ -0,0 +1,576 @@
+// Decoding of untrusted images, bounded by limits on their format, their dimensions and their
+// frames. It is kept free of the WASI dependencies of the server, so that it builds natively, e.g.
+// for the fuzz targets.
+use image::io::Reader;
+use image::{DynamicImage, ImageBuffer, ImageFormat, Rgba, RgbaImage};
+use std::fmt;
+use std::io::Cursor;
+
+// Limits applied to the images decoded from untrusted bytes.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    // Maximum width and height in pixels declared by an image header
+    pub max_width: u32,
+    pub max_height: u32,
+    // Maximum number of pixels (width x height) an image may decode to
+    pub max_pixels: u64,
+    // Maximum number of frames walked through in a single animated or multi-page image
+    pub max_decoded_frames: usize,
+    // Image formats accepted for decoding
+    pub allowed_formats: Vec<ImageFormat>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 8192,
+            max_height: 8192,
+            max_pixels: 32 * 1024 * 1024,
+            max_decoded_frames: 256,
+            allowed_formats: vec![
+                ImageFormat::Jpeg,
+                ImageFormat::Png,
+                ImageFormat::Gif,
+                ImageFormat::WebP,
+                ImageFormat::Bmp,
+                ImageFormat::Tiff,
+            ],
+        }
+    }
+}
+
+// The ways in which decoding an image can fail.
+#[derive(Debug)]
+pub enum DecodeError {
+    // The input is not an image in an allowed format, or it is malformed
+    InvalidImage(String),
+    // The image exceeds the limits
+    TooLarge(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::InvalidImage(msg) => write!(f, "invalid image: {}", msg),
+            DecodeError::TooLarge(msg) => write!(f, "image too large: {}", msg),
+        }
+    }
+}
+
+// Decode untrusted image bytes into an image. The format is detected from the magic bytes and
+// checked against the allowed formats, and the dimensions declared in the header are checked
+// against the limits before any pixel data is decoded, so that a small file cannot expand into an
+// arbitrarily large image (a "decompression bomb").
+pub fn decode_image(buf: &[u8], limits: &Limits) -> Result<DynamicImage, DecodeError> {
+    let format = check_header(buf, limits)?;
+    if format == ImageFormat::Gif {
+        return GifFrames::new(buf)?
+            .next_frame(limits)?
+            .ok_or_else(|| DecodeError::InvalidImage("GIF image has no frames".to_string()));
+    }
+    Reader::with_format(Cursor::new(buf), format)
+        .decode()
+        .map_err(|e| DecodeError::InvalidImage(format!("failed to decode image: {}", e)))
+}
+
+// The MIME type of an image, detected from its magic bytes, or None when it is not an image in a
+// format the server can decode.
+pub fn mime_type(buf: &[u8]) -> Option<&'static str> {
+    let mime = match image::guess_format(buf).ok()? {
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Png => "image/png",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Bmp => "image/bmp",
+        ImageFormat::Tiff => "image/tiff",
+        ImageFormat::Ico => "image/x-icon",
+        ImageFormat::Pnm => "image/x-portable-anymap",
+        ImageFormat::Tga => "image/x-tga",
+        ImageFormat::Hdr => "image/vnd.radiance",
+        ImageFormat::Dds => "image/vnd.ms-dds",
+        ImageFormat::Farbfeld => "image/x-farbfeld",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+// Decode the frames of an animated GIF or the pages of a multi-page TIFF one at a time, passing
+// every interval-th frame and its index to 'f'. Only one frame is held in memory at a time, and
+// decoding stops after max_frames frames are passed on, or after the limit of frames are walked
+// through, whichever comes first. Any other image is passed on as its only frame. The errors of
+// 'f' are passed on as they are.
+pub fn for_each_frame<F, E>(
+    buf: &[u8],
+    interval: usize,
+    max_frames: usize,
+    limits: &Limits,
+    mut f: F,
+) -> Result<(), E>
+where
+    F: FnMut(usize, DynamicImage) -> Result<(), E>,
+    E: From<DecodeError>,
+{
+    let format = check_header(buf, limits)?;
+    match format {
+        ImageFormat::Gif => {
+            let mut frames = GifFrames::new(buf)?;
+            let mut index = 0;
+            let mut decoded = 0;
+            while decoded < max_frames && index < limits.max_decoded_frames {
+                let Some(frame) = frames.next_frame(limits)? else {
+                    break;
+                };
+                if index % interval == 0 {
+                    f(index, frame)?;
+                    decoded += 1;
+                }
+                index += 1;
+            }
+            Ok(())
+        }
+        ImageFormat::Tiff => for_each_tiff_page(buf, interval, max_frames, limits, f),
+        _ => f(0, decode_image(buf, limits)?),
+    }
+}
+
+// The frames of a GIF, each composited onto the logical screen as left by the previous ones. The
+// frames of a GIF need not fit its logical screen, so the dimensions of every frame are checked
+// against the limits before its pixel data is decoded.
+struct GifFrames<'a> {
+    decoder: gif::Decoder<Cursor<&'a [u8]>>,
+    screen: RgbaImage,
+}
+
+impl<'a> GifFrames<'a> {
+    fn new(buf: &'a [u8]) -> Result<GifFrames<'a>, DecodeError> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let decoder = options.read_info(Cursor::new(buf)).map_err(invalid_gif)?;
+        let screen = RgbaImage::new(decoder.width() as u32, decoder.height() as u32);
+        Ok(GifFrames { decoder, screen })
+    }
+
+    // Decode the next frame, or return None after the last one.
+    fn next_frame(&mut self, limits: &Limits) -> Result<Option<DynamicImage>, DecodeError> {
+        let Some(frame) = self.decoder.next_frame_info().map_err(invalid_gif)? else {
+            return Ok(None);
+        };
+        let (left, top) = (frame.left as u32, frame.top as u32);
+        let (width, height) = (frame.width as u32, frame.height as u32);
+        let dispose = frame.dispose;
+        check_dimensions(width, height, limits)?;
+        let mut data = vec![0; self.decoder.buffer_size()];
+        self.decoder
+            .read_into_buffer(&mut data)
+            .map_err(invalid_gif)?;
+
+        // Transparent pixels, and the parts of the screen outside the frame, show the previous
+        // frames
+        let mut image = self.screen.clone();
+        for (i, pixel) in data.chunks_exact(4).enumerate() {
+            let (x, y) = (left + i as u32 % width, top + i as u32 / width);
+            if pixel[3] != 0 && x < image.width() && y < image.height() {
+                image.put_pixel(x, y, Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
+            }
+        }
+        match dispose {
+            gif::DisposalMethod::Any | gif::DisposalMethod::Keep => self.screen = image.clone(),
+            gif::DisposalMethod::Background => {
+                for y in top..(top + height).min(self.screen.height()) {
+                    for x in left..(left + width).min(self.screen.width()) {
+                        self.screen.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                    }
+                }
+            }
+            gif::DisposalMethod::Previous => {}
+        }
+        Ok(Some(DynamicImage::ImageRgba8(image)))
+    }
+}
+
+fn invalid_gif(e: gif::DecodingError) -> DecodeError {
+    DecodeError::InvalidImage(format!("failed to decode GIF frame: {}", e))
+}
+
+// Walk the pages of a TIFF file. The image crate only decodes the first page, so the pages are
+// read with the underlying tiff decoder and converted to images here.
+fn for_each_tiff_page<F, E>(
+    buf: &[u8],
+    interval: usize,
+    max_frames: usize,
+    limits: &Limits,
+    mut f: F,
+) -> Result<(), E>
+where
+    F: FnMut(usize, DynamicImage) -> Result<(), E>,
+    E: From<DecodeError>,
+{
+    let invalid = |e: tiff::TiffError| {
+        DecodeError::InvalidImage(format!("failed to decode TIFF page: {}", e))
+    };
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(buf)).map_err(invalid)?;
+    let mut index = 0;
+    let mut decoded = 0;
+    loop {
+        if index % interval == 0 {
+            let (width, height) = decoder.dimensions().map_err(invalid)?;
+            check_dimensions(width, height, limits)?;
+            let color_type = decoder.colortype().map_err(invalid)?;
+            let page = decoder.read_image().map_err(invalid)?;
+            f(index, tiff_page_to_image(width, height, color_type, page)?)?;
+            decoded += 1;
+        }
+        if decoded >= max_frames || index + 1 >= limits.max_decoded_frames || !decoder.more_images()
+        {
+            return Ok(());
+        }
+        decoder.next_image().map_err(invalid)?;
+        index += 1;
+    }
+}
+
+// Convert the raw samples of a TIFF page into an image of the matching color type.
+fn tiff_page_to_image(
+    width: u32,
+    height: u32,
+    color_type: tiff::ColorType,
+    page: tiff::decoder::DecodingResult,
+) -> Result<DynamicImage, DecodeError> {
+    use tiff::decoder::DecodingResult;
+    use tiff::ColorType;
+
+    let img = match (color_type, page) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        }
+        (color_type, _) => {
+            return Err(DecodeError::InvalidImage(format!(
+                "unsupported TIFF color type {:?}",
+                color_type
+            )))
+        }
+    };
+    img.ok_or_else(|| DecodeError::InvalidImage("truncated TIFF page data".to_string()))
+}
+
+// Check the format and the header of an image against the limits, returning the detected format.
+fn check_header(buf: &[u8], limits: &Limits) -> Result<ImageFormat, DecodeError> {
+    let format = image::guess_format(buf)
+        .map_err(|_| DecodeError::InvalidImage("unrecognized image format".to_string()))?;
+    if !limits.allowed_formats.contains(&format) {
+        return Err(DecodeError::InvalidImage(format!(
+            "image format {:?} is not allowed",
+            format
+        )));
+    }
+
+    let (width, height) = Reader::with_format(Cursor::new(buf), format)
+        .into_dimensions()
+        .map_err(|e| DecodeError::InvalidImage(format!("failed to read image header: {}", e)))?;
+    check_dimensions(width, height, limits)?;
+    Ok(format)
+}
+
+// Reject empty images and images whose dimensions exceed the limits.
+fn check_dimensions(width: u32, height: u32, limits: &Limits) -> Result<(), DecodeError> {
+    if width == 0 || height == 0 {
+        return Err(DecodeError::InvalidImage(format!(
+            "image has no pixels ({}x{})",
+            width, height
+        )));
+    }
+    if width > limits.max_width || height > limits.max_height {
+        return Err(DecodeError::TooLarge(format!(
+            "image dimensions {}x{} exceed the limit of {}x{}",
+            width, height, limits.max_width, limits.max_height
+        )));
+    }
+    let pixels = width as u64 * height as u64;
+    if pixels > limits.max_pixels {
+        return Err(DecodeError::TooLarge(format!(
+            "image has {} pixels, more than the limit of {}",
+            pixels, limits.max_pixels
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Frame, GenericImageView};
+
+    static BANANA: &[u8] = include_bytes!("../../test/banana.jpg");
+    static LEMON: &[u8] = include_bytes!("../../test/lemon.png");
+
+    // A small deterministic generator, so that failures can be reproduced
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    fn encode(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut buf = Vec::new();
+        img.write_to(&mut buf, format).unwrap();
+        buf
+    }
+
+    fn small_image() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(24, 16, |x, y| {
+            image::Rgba([(x * 10) as u8, (y * 15) as u8, 128, 200])
+        }))
+    }
+
+    fn animated_gif(frames: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut encoder = GifEncoder::new(&mut buf);
+        for i in 0..frames {
+            let frame = RgbaImage::from_pixel(8, 8, image::Rgba([(i * 40) as u8, 0, 0, 255]));
+            encoder.encode_frame(Frame::new(frame)).unwrap();
+        }
+        drop(encoder);
+        buf
+    }
+
+    fn multi_page_tiff(pages: usize) -> Vec<u8> {
+        let mut buf = Cursor::new(Vec::new());
+        let mut encoder = tiff::encoder::TiffEncoder::new(&mut buf).unwrap();
+        for i in 0..pages {
+            let page = vec![(i * 40) as u8; 8 * 8 * 3];
+            encoder
+                .write_image::<tiff::encoder::colortype::RGB8>(8, 8, &page)
+                .unwrap();
+        }
+        buf.into_inner()
+    }
+
+    // A BMP header declaring the given dimensions, followed by no pixel data
+    fn bmp_header(width: i32, height: i32) -> Vec<u8> {
+        let mut buf = b"BM".to_vec();
+        buf.extend_from_slice(&54u32.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&54u32.to_le_bytes());
+        buf.extend_from_slice(&40u32.to_le_bytes());
+        buf.extend_from_slice(&width.to_le_bytes());
+        buf.extend_from_slice(&height.to_le_bytes());
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        buf.extend_from_slice(&24u16.to_le_bytes());
+        buf.extend_from_slice(&[0; 24]);
+        buf
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        let img = small_image();
+        vec![
+            BANANA.to_vec(),
+            LEMON.to_vec(),
+            encode(&img, ImageFormat::Png),
+            encode(&DynamicImage::ImageRgb8(img.to_rgb8()), ImageFormat::Jpeg),
+            encode(&img, ImageFormat::Bmp),
+            multi_page_tiff(3),
+            animated_gif(4),
+        ]
+    }
+
+    // Run both entry points on an input. A panic fails the test.
+    fn decode_all(buf: &[u8]) -> (bool, bool) {
+        let limits = Limits::default();
+        let decoded = decode_image(buf, &limits).is_ok();
+        let framed = for_each_frame(buf, 1, 4, &limits, |_, _| Ok::<_, DecodeError>(())).is_ok();
+        (decoded, framed)
+    }
+
+    // Walk the frames of an image with the default limits, collecting the indices passed on.
+    fn frame_indices(buf: &[u8], interval: usize, max_frames: usize) -> Vec<usize> {
+        let mut indices = Vec::new();
+        for_each_frame(buf, interval, max_frames, &Limits::default(), |index, _| {
+            indices.push(index);
+            Ok::<_, DecodeError>(())
+        })
+        .unwrap();
+        indices
+    }
+
+    #[test]
+    fn decodes_valid_images() {
+        for sample in samples() {
+            assert_eq!(decode_all(&sample), (true, true));
+        }
+    }
+
+    #[test]
+    fn truncated_images_do_not_panic() {
+        for sample in samples() {
+            let step = (sample.len() / 200).max(1);
+            for len in (0..sample.len()).step_by(step) {
+                decode_all(&sample[..len]);
+            }
+            // Cut inside the magic bytes or the header, nothing can be decoded
+            for len in 0..8 {
+                assert_eq!(decode_all(&sample[..len]), (false, false));
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_images_do_not_panic() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for sample in samples() {
+            for _ in 0..100 {
+                let mut corrupted = sample.clone();
+                for _ in 0..1 + rng.next() % 16 {
+                    let at = rng.next() as usize % corrupted.len();
+                    corrupted[at] = rng.next() as u8;
+                }
+                decode_all(&corrupted);
+            }
+        }
+    }
+
+    #[test]
+    fn random_bytes_do_not_panic() {
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        let magics: [&[u8]; 7] = [
+            b"",
+            b"\x89PNG\r\n\x1a\n",
+            b"\xff\xd8\xff",
+            b"GIF89a",
+            b"BM",
+            b"II*\0",
+            b"RIFF\0\0\0\0WEBP",
+        ];
+        for magic in magics {
+            for len in [0, 1, 16, 64, 512, 4096] {
+                let mut buf = magic.to_vec();
+                buf.extend(rng.bytes(len));
+                decode_all(&buf);
+            }
+        }
+        assert!(matches!(
+            decode_image(&rng.bytes(1024), &Limits::default()),
+            Err(DecodeError::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        let limits = Limits::default();
+        let too_wide = bmp_header(limits.max_width as i32 + 1, 1);
+        let too_many_pixels = bmp_header(8000, 8000);
+        for buf in [too_wide, too_many_pixels] {
+            assert!(matches!(
+                decode_image(&buf, &limits),
+                Err(DecodeError::TooLarge(_))
+            ));
+            assert!(matches!(
+                for_each_frame(&buf, 1, 1, &limits, |_, _| Ok(())),
+                Err(DecodeError::TooLarge(_))
+            ));
+        }
+        assert!(decode_image(&bmp_header(i32::MAX, i32::MIN), &limits).is_err());
+    }
+
+    #[test]
+    fn decodes_gif_frames_larger_than_the_screen() {
+        let mut buf = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut buf, 4, 4, &[]).unwrap();
+        let mut pixels = vec![255; 8 * 8 * 4];
+        let frame = gif::Frame::from_rgba(8, 8, &mut pixels);
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+        let img = decode_image(&buf, &Limits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (4, 4));
+    }
+
+    #[test]
+    fn rejects_oversized_gif_frames() {
+        // An 8x8 logical screen with a global palette of two colors, holding a 9000x1 frame
+        let mut buf = b"GIF89a\x08\x00\x08\x00\x80\x00\x00".to_vec();
+        buf.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        buf.extend_from_slice(b"\x2c\x00\x00\x00\x00\x28\x23\x01\x00\x00\x02\x00\x3b");
+        assert!(matches!(
+            decode_image(&buf, &Limits::default()),
+            Err(DecodeError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_disallowed_formats() {
+        let ico = encode(&small_image(), ImageFormat::Ico);
+        assert!(matches!(
+            decode_image(&ico, &Limits::default()),
+            Err(DecodeError::InvalidImage(_))
+        ));
+        let limits = Limits {
+            allowed_formats: vec![ImageFormat::Ico],
+            ..Limits::default()
+        };
+        assert!(decode_image(&ico, &limits).is_ok());
+    }
+
+    #[test]
+    fn walks_the_frames_of_animated_gifs() {
+        assert_eq!(frame_indices(&animated_gif(5), 2, 32), [0, 2, 4]);
+    }
+
+    #[test]
+    fn bounds_the_frames_decoded_with_an_interval() {
+        let max_decoded = Limits::default().max_decoded_frames;
+        // The third frame to classify is past the frames that may be decoded
+        let interval = max_decoded / 2 + 1;
+        let frames = 2 * interval + 1;
+        for buf in [animated_gif(frames), multi_page_tiff(frames)] {
+            assert_eq!(frame_indices(&buf, interval, 32), [0, interval]);
+        }
+    }
+
+    #[test]
+    fn walks_the_pages_of_multi_page_tiffs() {
+        let mut indices = Vec::new();
+        for_each_frame(
+            &multi_page_tiff(5),
+            1,
+            3,
+            &Limits::default(),
+            |index, page| {
+                assert_eq!(page.to_rgb8().get_pixel(0, 0)[0], (index * 40) as u8);
+                indices.push(index);
+                Ok::<_, DecodeError>(())
+            },
+        )
+        .unwrap();
+        assert_eq!(indices, [0, 1, 2]);
+    }
+}
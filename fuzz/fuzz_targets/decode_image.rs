@@ -0,0 +1,12 @@
+// Feed arbitrary bytes to the decoder entry points: they may reject an input, but never panic or
+// allocate beyond the default limits.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wasm_ai_decode::{decode_image, for_each_frame, DecodeError, Limits};
+
+fuzz_target!(|data: &[u8]| {
+    let limits = Limits::default();
+    let _ = decode_image(data, &limits);
+    let _ = for_each_frame(data, 1, 4, &limits, |_, _| Ok::<_, DecodeError>(()));
+});
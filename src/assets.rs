@@ -0,0 +1,82 @@
+// Static assets of the web pages: the stylesheet, the script of the upload UI and the favicon.
+// They are embedded in the binary and revalidated by ETag. During development, a directory of
+// files can override them so that edits show up without a rebuild.
+use crate::config::CONFIG;
+use crate::inference::content_hash;
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Define static variables for the embedded assets, by name, with their content types
+static EMBEDDED: [(&str, &str, &[u8]); 3] = [
+    (
+        "style.css",
+        "text/css; charset=utf-8",
+        include_bytes!("static/style.css"),
+    ),
+    (
+        "upload.js",
+        "text/javascript; charset=utf-8",
+        include_bytes!("static/upload.js"),
+    ),
+    (
+        "favicon.ico",
+        "image/x-icon",
+        include_bytes!("static/favicon.ico"),
+    ),
+];
+
+// How long browsers may use an embedded asset before revalidating it. The URLs of the assets do
+// not change with their contents, so this is kept short.
+const MAX_AGE_SECS: u32 = 3600;
+
+pub struct Asset {
+    pub content_type: &'static str,
+    pub data: Cow<'static, [u8]>,
+    // Quoted hash of the contents
+    pub etag: String,
+    pub cache_control: String,
+}
+
+impl Asset {
+    // Whether an If-None-Match header names the current contents, so that they need not be sent.
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == self.etag
+        })
+    }
+}
+
+// Look up an asset by name: the file of the override directory when it has one, and otherwise the
+// embedded one. Only the names of embedded assets are served, so no other file of the directory is
+// ever exposed. Overridden assets must be revalidated on every use, since they may be edited.
+pub fn get(name: &str) -> Option<Asset> {
+    let (_, content_type, embedded) = EMBEDDED.iter().find(|(n, _, _)| *n == name)?;
+    let (data, cache_control) = match read_override(name) {
+        Some(data) => (Cow::Owned(data), "no-cache".to_string()),
+        None => (
+            Cow::Borrowed(*embedded),
+            format!("public, max-age={}", MAX_AGE_SECS),
+        ),
+    };
+    Some(Asset {
+        content_type,
+        etag: format!("\"{}\"", content_hash(&data)),
+        data,
+        cache_control,
+    })
+}
+
+fn read_override(name: &str) -> Option<Vec<u8>> {
+    let path = Path::new(CONFIG.assets.dir.as_ref()?).join(name);
+    match fs::read(&path) {
+        Ok(data) => Some(data),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("Failed to read asset {}: {}", path.display(), e);
+            None
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use wasm_ai_demo_app::inference::benchmark::{self, BenchmarkOptions};
+
+/// Measure the latency of each stage of the inference pipeline, and the throughput of the model
+/// at several batch sizes, on a single image.
+#[derive(Parser)]
+#[command(name = "benchmark")]
+struct Args {
+    /// Image to run through the pipeline
+    image: PathBuf,
+
+    /// Model to benchmark, instead of the default one
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Number of untimed warm-up iterations
+    #[arg(long, default_value_t = 5)]
+    warmup: usize,
+
+    /// Number of timed iterations
+    #[arg(long, default_value_t = 50)]
+    iterations: usize,
+
+    /// Batch sizes to measure the throughput at, separated by commas
+    #[arg(long, value_delimiter = ',', default_value = "1,2,4,8")]
+    batch_sizes: Vec<usize>,
+
+    /// Write the JSON results to this file instead of standard output
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let options = BenchmarkOptions {
+        model: args.model.clone(),
+        warmup: args.warmup,
+        iterations: args.iterations,
+        batch_sizes: args.batch_sizes.clone(),
+    };
+
+    let report = match benchmark::run_benchmark(&args.image.to_string_lossy(), &options) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error running benchmark: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    eprintln!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10}",
+        "STAGE", "MEAN", "P50", "P95", "P99"
+    );
+    for stage in &report.stages {
+        eprintln!(
+            "{:<12} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+            stage.stage,
+            stage.latency.mean_ms,
+            stage.latency.p50_ms,
+            stage.latency.p95_ms,
+            stage.latency.p99_ms
+        );
+    }
+    for batch in &report.batches {
+        match (&batch.images_per_second, &batch.error) {
+            (Some(throughput), _) => eprintln!(
+                "batch size {:>3}: {:.1} images/s",
+                batch.batch_size, throughput
+            ),
+            (None, Some(error)) => eprintln!("batch size {:>3}: {}", batch.batch_size, error),
+            (None, None) => {}
+        }
+    }
+
+    let json = match serde_json::to_string_pretty(&report) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Error serializing results: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match &args.output {
+        Some(path) => {
+            if let Err(e) = fs::write(path, json) {
+                eprintln!("Error writing {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+        None => println!("{}", json),
+    }
+    ExitCode::SUCCESS
+}
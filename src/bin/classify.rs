@@ -0,0 +1,206 @@
+use clap::{Parser, ValueEnum};
+use glob::Pattern;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use wasm_ai_demo_app::inference::{self, Aggregation, Classification, InferenceOptions, TtaMode};
+
+/// Classify image files with the same pipeline as the web server, without starting it.
+///
+/// Exits with status 1 when any image could not be classified.
+#[derive(Parser)]
+#[command(name = "classify")]
+struct Args {
+    /// Image files, or directories to search for images
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// Search directories recursively
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Only classify the files found in directories whose path matches one of these patterns
+    #[arg(short, long = "glob", value_name = "PATTERN")]
+    globs: Vec<Pattern>,
+
+    /// How the results are written to standard output
+    #[arg(short, long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    /// Model or ensemble to classify with, instead of the default one
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Classify every frame of animated GIFs and multi-page TIFFs
+    #[arg(long)]
+    all_frames: bool,
+
+    /// With --all-frames, only classify every n-th frame
+    #[arg(long, value_name = "N")]
+    frame_interval: Option<usize>,
+
+    /// How frame scores are combined: mean or vote
+    #[arg(long, default_value = "mean")]
+    aggregate: Aggregation,
+
+    /// Test-time augmentation: off, flip or full
+    #[arg(long, default_value = "off")]
+    tta: TtaMode,
+
+    /// Number of predictions reported for each image (default 5)
+    #[arg(long, value_name = "K")]
+    top_k: Option<usize>,
+
+    /// Also report the categories of the configured label hierarchy this many levels up
+    #[arg(long, value_name = "LEVELS")]
+    granularity: Option<usize>,
+
+    /// Label map applied to the results instead of the one of the model ("none" for none)
+    #[arg(long, value_name = "NAME")]
+    label_map: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Table,
+    Jsonl,
+    Csv,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let options = InferenceOptions {
+        all_frames: args.all_frames,
+        frame_interval: args.frame_interval,
+        aggregate: args.aggregate,
+        tta: args.tta,
+        model: args.model.clone(),
+        top_k: args.top_k,
+        granularity: args.granularity,
+        label_map: args.label_map.clone(),
+    };
+
+    // Gather the images first, so that the output is in a stable order
+    let mut images = Vec::new();
+    for path in &args.paths {
+        if path.is_dir() {
+            collect_images(path, &args, &mut images);
+        } else {
+            images.push((path.clone(), None));
+        }
+    }
+    if images.is_empty() {
+        eprintln!("No images found");
+        return ExitCode::FAILURE;
+    }
+
+    let mut failed = false;
+    print_header(args.format);
+    for (path, error) in images {
+        let result = match error {
+            Some(error) => Err(error),
+            None => {
+                inference::infer_image(&path.to_string_lossy(), &options).map_err(|e| e.to_string())
+            }
+        };
+        failed |= result.is_err();
+        print_result(args.format, &path, &result);
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+// Add the image files of a directory to 'images', in name order. Unreadable directories are
+// added with their error, so that they are reported like images that failed.
+fn collect_images(dir: &Path, args: &Args, images: &mut Vec<(PathBuf, Option<String>)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            images.push((
+                dir.to_path_buf(),
+                Some(format!("failed to read directory: {}", e)),
+            ));
+            return;
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            if args.recursive {
+                collect_images(&path, args, images);
+            }
+        } else if image::ImageFormat::from_path(&path).is_ok()
+            && (args.globs.is_empty() || args.globs.iter().any(|g| g.matches_path(&path)))
+        {
+            images.push((path, None));
+        }
+    }
+}
+
+fn print_header(format: Format) {
+    match format {
+        Format::Table => println!(
+            "{:<40} {:<12} {:>5} {:<30} {:>10}",
+            "PATH", "MODEL", "CLASS", "LABEL", "SCORE"
+        ),
+        Format::Jsonl => {}
+        Format::Csv => println!("path,model,rank,class_id,label,score,error"),
+    }
+}
+
+fn print_result(format: Format, path: &Path, result: &Result<Classification, String>) {
+    let path = path.to_string_lossy();
+    match (format, result) {
+        (Format::Table, Ok(classification)) => match classification.predictions.first() {
+            Some(top) => println!(
+                "{:<40} {:<12} {:>5} {:<30} {:>10.4}",
+                path, classification.model, top.class_id, top.label, top.probability
+            ),
+            // A label map may filter out every class, or no prediction be requested
+            None => println!("{:<40} {:<12} no prediction", path, classification.model),
+        },
+        (Format::Table, Err(error)) => println!("{:<40} error: {}", path, error),
+        (Format::Jsonl, Ok(classification)) => println!(
+            "{}",
+            serde_json::json!({ "path": path, "classification": classification })
+        ),
+        (Format::Jsonl, Err(error)) => {
+            println!("{}", serde_json::json!({ "path": path, "error": error }))
+        }
+        (Format::Csv, Ok(classification)) => {
+            if classification.predictions.is_empty() {
+                println!(
+                    "{},{},,,,,",
+                    csv_field(&path),
+                    csv_field(&classification.model)
+                );
+            }
+            for (rank, prediction) in classification.predictions.iter().enumerate() {
+                println!(
+                    "{},{},{},{},{},{},",
+                    csv_field(&path),
+                    csv_field(&classification.model),
+                    rank + 1,
+                    prediction.class_id,
+                    csv_field(&prediction.label),
+                    prediction.probability
+                );
+            }
+        }
+        (Format::Csv, Err(error)) => println!("{},,,,,,{}", csv_field(&path), csv_field(error)),
+    }
+}
+
+// Quote a CSV field when it contains a separator, a quote or a line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,117 @@
+use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tera::Context;
+use wasm_ai_demo_app::evaluation::{self, Report};
+use wasm_ai_demo_app::inference::labels::NO_LABEL_MAP;
+use wasm_ai_demo_app::inference::{Aggregation, InferenceOptions, TtaMode};
+use wasm_ai_demo_app::templates::render_template_context;
+
+/// Evaluate a model on a labelled dataset before deploying it.
+///
+/// The dataset is either an ImageFolder-style directory, with one subdirectory of images per
+/// label, or a CSV manifest with a path and a label on each line. Labels are ImageNet class IDs
+/// or class names.
+#[derive(Parser)]
+#[command(name = "evaluate")]
+struct Args {
+    /// Dataset directory or CSV manifest
+    dataset: PathBuf,
+
+    /// Write the JSON report to this file instead of standard output
+    #[arg(long, value_name = "PATH")]
+    json: Option<PathBuf>,
+
+    /// Also write an HTML report to this file
+    #[arg(long, value_name = "PATH")]
+    html: Option<PathBuf>,
+
+    /// Number of worst misclassified images to report
+    #[arg(long, default_value_t = 20)]
+    worst: usize,
+
+    /// Model or ensemble to evaluate, instead of the default one
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Classify every frame of animated GIFs and multi-page TIFFs
+    #[arg(long)]
+    all_frames: bool,
+
+    /// With --all-frames, only classify every n-th frame
+    #[arg(long, value_name = "N")]
+    frame_interval: Option<usize>,
+
+    /// How frame scores are combined: mean or vote
+    #[arg(long, default_value = "mean")]
+    aggregate: Aggregation,
+
+    /// Test-time augmentation: off, flip or full
+    #[arg(long, default_value = "off")]
+    tta: TtaMode,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let options = InferenceOptions {
+        all_frames: args.all_frames,
+        frame_interval: args.frame_interval,
+        aggregate: args.aggregate,
+        tta: args.tta,
+        model: args.model.clone(),
+        top_k: None,
+        granularity: None,
+        // Results are compared with ImageNet labels, before any label map
+        label_map: Some(NO_LABEL_MAP.to_string()),
+    };
+
+    let examples = if args.dataset.is_dir() {
+        evaluation::load_image_folder(&args.dataset)
+    } else {
+        evaluation::load_manifest(&args.dataset)
+    };
+    let examples = match examples {
+        Ok(examples) if examples.is_empty() => {
+            eprintln!("No images found in {}", args.dataset.display());
+            return ExitCode::FAILURE;
+        }
+        Ok(examples) => examples,
+        Err(e) => {
+            eprintln!("Error loading dataset: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let report = evaluation::evaluate(&examples, &options, args.worst);
+    eprintln!(
+        "{}: top-1 accuracy {:.4}, top-5 accuracy {:.4} on {} images ({} failed)",
+        report.model,
+        report.top1_accuracy,
+        report.top5_accuracy,
+        report.examples,
+        report.failures.len()
+    );
+
+    if let Err(e) = write_reports(&args, &report) {
+        eprintln!("Error writing report: {}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn write_reports(args: &Args, report: &Report) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(report).map_err(|e| e.to_string())?;
+    match &args.json {
+        Some(path) => fs::write(path, json).map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => println!("{}", json),
+    }
+
+    if let Some(path) = &args.html {
+        let mut context = Context::new();
+        context.insert("report", report);
+        let html = render_template_context("evaluation.html", &context)?;
+        fs::write(path, html).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
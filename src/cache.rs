@@ -0,0 +1,274 @@
+use crate::config::CONFIG;
+use crate::inference::hierarchy::HIERARCHY;
+use crate::inference::labels;
+use crate::inference::{content_hash, Classification, InferenceError, InferenceOptions, REGISTRY};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+// Define a lazy-static variable to store the cached results, loaded from disk when persisted
+lazy_static! {
+    static ref CACHE: Mutex<ResultCache> = Mutex::new(ResultCache::load(
+        CONFIG.cache.persist.as_deref(),
+        CONFIG.cache.capacity
+    ));
+}
+
+// A cached result, as persisted on a line of the cache file.
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    key: String,
+    classification: Classification,
+}
+
+// A bounded cache of classifications that evicts the least recently used ones.
+struct ResultCache {
+    // Results by key, with the tick of their last use
+    entries: HashMap<String, (Classification, u64)>,
+    // Keys by the tick of their last use, least recent first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    // Most results kept
+    capacity: usize,
+    // Number of lines in the cache file, which is compacted once it holds many evicted entries
+    persisted_lines: usize,
+}
+
+impl ResultCache {
+    // Load the results persisted in a file, if any. A cache that cannot be read only costs
+    // performance, so it starts empty instead of failing.
+    fn load(persist: Option<&str>, capacity: usize) -> ResultCache {
+        let mut cache = ResultCache {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            capacity,
+            persisted_lines: 0,
+        };
+        let Some(path) = persist else {
+            return cache;
+        };
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    if let Ok(entry) = serde_json::from_str::<Entry>(line) {
+                        cache.insert(entry.key, entry.classification);
+                    }
+                    cache.persisted_lines += 1;
+                }
+                eprintln!(
+                    "Loaded {} cached results from: {}",
+                    cache.entries.len(),
+                    path
+                );
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to read cache {}: {}", path, e),
+        }
+        cache
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &str) -> Option<Classification> {
+        let tick = self.next_tick();
+        let (classification, last_use) = self.entries.get_mut(key)?;
+        self.recency.remove(last_use);
+        *last_use = tick;
+        self.recency.insert(tick, key.to_string());
+        Some(classification.clone())
+    }
+
+    fn insert(&mut self, key: String, classification: Classification) {
+        let tick = self.next_tick();
+        if let Some((_, last_use)) = self.entries.insert(key.clone(), (classification, tick)) {
+            self.recency.remove(&last_use);
+        }
+        self.recency.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    // Append a new result to the cache file. Evicted results stay in the file until it holds
+    // twice as many lines as the cache can, then it is rewritten with the cached results only.
+    fn persist(&mut self, path: &str, key: &str) -> io::Result<()> {
+        if self.persisted_lines >= 2 * self.capacity.max(1) {
+            let mut contents = String::new();
+            for key in self.recency.values() {
+                contents.push_str(&self.line(key)?);
+            }
+            fs::write(path, contents)?;
+            self.persisted_lines = self.entries.len();
+            return Ok(());
+        }
+        let line = self.line(key)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())?;
+        self.persisted_lines += 1;
+        Ok(())
+    }
+
+    fn line(&self, key: &str) -> io::Result<String> {
+        let entry = Entry {
+            key: key.to_string(),
+            classification: self.entries[key].0.clone(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+// Build the cache key of a request: the hash of the image, and the result ID of its options.
+pub fn key(image: &[u8], options: &InferenceOptions) -> Result<String, InferenceError> {
+    Ok(format!("{}-{}", content_hash(image), result_id(options)?))
+}
+
+// Hash everything besides the image that the result of a request depends on, i.e. the versions
+// of the models, the options and the preprocessing configuration. Together with the ID of an
+// image, it identifies a result.
+pub fn result_id(options: &InferenceOptions) -> Result<String, InferenceError> {
+    let served = REGISTRY.resolve(options.model.as_deref())?;
+    let frames = if options.all_frames {
+        format!(
+            "every {} of {} frames",
+            options.frame_interval.unwrap_or(1).max(1),
+            CONFIG.limits.max_frames
+        )
+    } else {
+        "first frame".to_string()
+    };
+    let hierarchy = match (options.granularity, &*HIERARCHY) {
+        (Some(levels), Some(hierarchy)) => {
+            format!("{} levels of {}", levels, hierarchy.fingerprint)
+        }
+        _ => "classes".to_string(),
+    };
+    let label_map = match labels::resolve(options.label_map.as_deref(), &served)? {
+        Some(label_map) => label_map.fingerprint.clone(),
+        None => "no label map".to_string(),
+    };
+    let setup = format!(
+        "{}|{}|{:?}|{:?}|{:?}|{}|{}|{:?}|{:?}",
+        REGISTRY.fingerprint(&served),
+        frames,
+        options.aggregate,
+        options.tta,
+        options.top_k,
+        hierarchy,
+        label_map,
+        CONFIG.preprocess.background,
+        CONFIG.open_set
+    );
+    Ok(content_hash(setup.as_bytes()))
+}
+
+// Look up the cached result of a request.
+pub fn get(key: &str) -> Option<Classification> {
+    if !CONFIG.cache.enabled {
+        return None;
+    }
+    CACHE.lock().unwrap().get(key)
+}
+
+// Cache the result of a request, and persist it when the cache is persisted.
+pub fn insert(key: &str, classification: &Classification) {
+    if !CONFIG.cache.enabled {
+        return;
+    }
+    let mut cache = CACHE.lock().unwrap();
+    cache.insert(key.to_string(), classification.clone());
+    if let Some(path) = &CONFIG.cache.persist {
+        // A result that cannot be persisted is still cached in memory
+        if cache.entries.contains_key(key) {
+            if let Err(e) = cache.persist(path, key) {
+                eprintln!("Failed to persist cached result to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+// Drop every cached result, in memory and on disk, and return how many there were.
+pub fn clear() -> Result<usize, String> {
+    let mut cache = CACHE.lock().unwrap();
+    let cleared = cache.entries.len();
+    cache.entries.clear();
+    cache.recency.clear();
+    if let Some(path) = &CONFIG.cache.persist {
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        }
+        cache.persisted_lines = 0;
+    }
+    Ok(cleared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classification(model: &str) -> Classification {
+        serde_json::from_value(serde_json::json!({ "model": model, "predictions": [] })).unwrap()
+    }
+
+    fn cached(cache: &mut ResultCache, key: &str) -> Option<String> {
+        cache.get(key).map(|classification| classification.model)
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_results() {
+        let mut cache = ResultCache::load(None, 2);
+        cache.insert("a".to_string(), classification("a"));
+        cache.insert("b".to_string(), classification("b"));
+        // Using a makes b the least recently used
+        assert_eq!(cached(&mut cache, "a").as_deref(), Some("a"));
+        cache.insert("c".to_string(), classification("c"));
+        assert_eq!(cached(&mut cache, "b"), None);
+        assert_eq!(cached(&mut cache, "a").as_deref(), Some("a"));
+        assert_eq!(cached(&mut cache, "c").as_deref(), Some("c"));
+        // Replacing a result refreshes it
+        cache.insert("a".to_string(), classification("a2"));
+        cache.insert("d".to_string(), classification("d"));
+        assert_eq!(cached(&mut cache, "c"), None);
+        assert_eq!(cached(&mut cache, "a").as_deref(), Some("a2"));
+    }
+
+    #[test]
+    fn reloads_the_results_after_compaction() {
+        let path = "cache-compaction-test.jsonl";
+        let _ = fs::remove_file(path);
+        let mut cache = ResultCache::load(Some(path), 2);
+        for key in ["a", "b", "c", "d", "e"] {
+            cache.insert(key.to_string(), classification(key));
+            cache.persist(path, key).unwrap();
+        }
+        // The fifth result found 4 lines, twice the capacity, and rewrote the file
+        let lines = fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!((cache.persisted_lines, lines), (2, 2));
+
+        let mut reloaded = ResultCache::load(Some(path), 2);
+        fs::remove_file(path).unwrap();
+        assert_eq!(reloaded.persisted_lines, 2);
+        assert_eq!(cached(&mut reloaded, "c"), None);
+        // The results keep their order of use, so d is the first to go
+        reloaded.insert("f".to_string(), classification("f"));
+        assert_eq!(cached(&mut reloaded, "d"), None);
+        assert_eq!(cached(&mut reloaded, "e").as_deref(), Some("e"));
+        assert_eq!(cached(&mut reloaded, "f").as_deref(), Some("f"));
+    }
+}
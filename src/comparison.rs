@@ -0,0 +1,117 @@
+// Side-by-side comparison of models on one image, for evaluating a new model version against the
+// ones already served: the results of every model, and where their predictions agree.
+use crate::inference::{Classification, InferenceError, REGISTRY};
+use serde::Serialize;
+use std::cmp::Reverse;
+
+// The result of one of the compared models.
+#[derive(Debug, Serialize)]
+pub struct ModelResult {
+    // Time taken to classify the image, in milliseconds
+    pub elapsed_ms: f64,
+    // Whether the result was served from the cache
+    pub cached: bool,
+    #[serde(flatten)]
+    pub classification: Classification,
+}
+
+// A label that is the top prediction of some of the models.
+#[derive(Debug, Serialize)]
+pub struct TopLabel {
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub localized_label: Option<String>,
+    pub models: Vec<String>,
+}
+
+// Where the predictions of the models agree.
+#[derive(Debug, Serialize)]
+pub struct Agreement {
+    // Whether every model has the same top label
+    pub unanimous: bool,
+    // The top labels of the models, the one picked by the most models first
+    pub top_labels: Vec<TopLabel>,
+    // The labels among the predictions of every model, in the order of the first model
+    pub shared: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub results: Vec<ModelResult>,
+    pub agreement: Agreement,
+}
+
+impl Comparison {
+    // Compare the results of the models by label, so that models with different label maps or
+    // label spaces are compared on what they report.
+    pub fn new(results: Vec<ModelResult>) -> Comparison {
+        let mut top_labels: Vec<TopLabel> = Vec::new();
+        for result in &results {
+            let Some(top) = result.classification.predictions.first() else {
+                continue;
+            };
+            let model = result.classification.model.clone();
+            match top_labels.iter_mut().find(|t| t.label == top.label) {
+                Some(top_label) => top_label.models.push(model),
+                None => top_labels.push(TopLabel {
+                    label: top.label.clone(),
+                    localized_label: top.localized_label.clone(),
+                    models: vec![model],
+                }),
+            }
+        }
+        // A stable sort keeps the order of the models among labels picked by as many
+        top_labels.sort_by_key(|t| Reverse(t.models.len()));
+
+        let predicted_by_all = |label: &str| {
+            results.iter().all(|result| {
+                let predictions = &result.classification.predictions;
+                predictions.iter().any(|p| p.label == label)
+            })
+        };
+        let shared = match results.first() {
+            Some(first) => first
+                .classification
+                .predictions
+                .iter()
+                .filter(|p| predicted_by_all(&p.label))
+                .map(|p| p.label.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Comparison {
+            agreement: Agreement {
+                unanimous: top_labels.len() == 1 && top_labels[0].models.len() == results.len(),
+                top_labels,
+                shared,
+            },
+            results,
+        }
+    }
+}
+
+// The models to compare: those of a comma-separated list of models and ensembles, or every one
+// that is served when there is no list.
+pub fn model_names(list: Option<&str>) -> Result<Vec<String>, InferenceError> {
+    let mut names: Vec<String> = Vec::new();
+    match list {
+        Some(list) => {
+            for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                if REGISTRY.get(name).is_none() {
+                    return Err(InferenceError::UnknownModel(name.to_string()));
+                }
+                if !names.iter().any(|n| n == name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        None => names.extend(REGISTRY.names().map(String::from)),
+    }
+    if names.len() < 2 {
+        return Err(InferenceError::InvalidInput(
+            "a comparison needs at least two models".to_string(),
+        ));
+    }
+    Ok(names)
+}
@@ -0,0 +1,437 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{env, fs, io};
+
+// Default location of the configuration file, relative to the preopened directory. It can be
+// overridden by setting the WASM_AI_CONFIG environment variable.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+// Define a lazy-static variable to store the deployment configuration
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load();
+}
+
+// Deployment configuration. Every section is optional and falls back to its defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub limits: Limits,
+    pub preprocess: Preprocess,
+    pub history: History,
+    pub cache: Cache,
+    pub images: Images,
+    pub explain: Explain,
+    pub open_set: OpenSet,
+    pub locales: Locales,
+    pub assets: Assets,
+    pub rollout: Rollout,
+    pub admin: Admin,
+    // Name of the model or ensemble used when a request does not pick one
+    pub default_model: Option<String>,
+    // File of "child<TAB>parent" lines grouping the classes into categories, for the granularity
+    // option
+    pub hierarchy: Option<String>,
+    // Files remapping, allowing and denying classes, by the name that models and requests use
+    pub label_maps: BTreeMap<String, String>,
+    pub models: Vec<ModelConfig>,
+    pub ensembles: Vec<EnsembleConfig>,
+}
+
+impl Config {
+    // Load the configuration file, falling back to the defaults when it does not exist. An
+    // invalid file is a deployment error, so it aborts instead of being silently ignored.
+    fn load() -> Config {
+        let path = env::var("WASM_AI_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let config = toml::from_str(&contents)
+                    .unwrap_or_else(|e| panic!("Invalid configuration in {}: {}", path, e));
+                eprintln!("Loaded configuration from: {}", path);
+                config
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => panic!("Failed to read configuration {}: {}", path, e),
+        }
+    }
+}
+
+// Limits applied to request bodies and to the images decoded from them.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    // Maximum size in bytes of a request body on any route
+    pub max_body_bytes: u64,
+    // Maximum width and height in pixels declared by an image header
+    pub max_width: u32,
+    pub max_height: u32,
+    // Maximum number of pixels (width x height) an image may decode to
+    pub max_pixels: u64,
+    // Maximum number of frames classified from a single animated or multi-page image
+    pub max_frames: usize,
+    // Maximum number of frames walked through in a single animated or multi-page image, counting
+    // those skipped by frame_interval: the frames of a GIF are drawn over the previous ones, so
+    // they must be decoded even when they are not classified
+    pub max_decoded_frames: usize,
+    // Image formats accepted for decoding, named by file extension (e.g. "jpeg", "png")
+    pub allowed_formats: Vec<String>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_bytes: 5 * 1024 * 1024,
+            max_width: 8192,
+            max_height: 8192,
+            max_pixels: 32 * 1024 * 1024,
+            max_frames: 32,
+            max_decoded_frames: 256,
+            allowed_formats: ["jpeg", "png", "gif", "webp", "bmp", "tiff"]
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl Limits {
+    // The limits of the image decoder. Allowed formats that name no image format are ignored.
+    pub fn decode_limits(&self) -> wasm_ai_decode::Limits {
+        wasm_ai_decode::Limits {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_pixels: self.max_pixels,
+            max_decoded_frames: self.max_decoded_frames,
+            allowed_formats: self
+                .allowed_formats
+                .iter()
+                .filter_map(image::ImageFormat::from_extension)
+                .collect(),
+        }
+    }
+}
+
+// Options for preparing a decoded image before it is resized into the input tensor.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preprocess {
+    // RGB color that transparent pixels are composited onto
+    pub background: [u8; 3],
+}
+
+impl Default for Preprocess {
+    fn default() -> Self {
+        Preprocess {
+            background: [255, 255, 255],
+        }
+    }
+}
+
+// Where and how the classifications served by the web server are recorded.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct History {
+    pub enabled: bool,
+    // Directory of the store, relative to the preopened directory
+    pub dir: String,
+    // Number of records shown per page when a request does not pick one
+    pub page_size: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            enabled: true,
+            dir: "history".to_string(),
+            page_size: 20,
+        }
+    }
+}
+
+// The cache of classification results, for images that are submitted more than once.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    pub enabled: bool,
+    // Maximum number of cached results; the least recently used ones are evicted first
+    pub capacity: usize,
+    // File the cache is persisted to, so that it survives restarts. Kept in memory only when unset
+    pub persist: Option<String>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            enabled: true,
+            capacity: 1024,
+            persist: None,
+        }
+    }
+}
+
+// The thumbnail and annotated copy made of every uploaded image.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Images {
+    // Directory of the copies, relative to the preopened directory
+    pub dir: String,
+    // Largest width and height of the thumbnails, in pixels
+    pub thumbnail_size: u32,
+    // Largest width and height of the annotated copies, without their caption bar
+    pub annotated_size: u32,
+}
+
+impl Default for Images {
+    fn default() -> Self {
+        Images {
+            dir: "images".to_string(),
+            thumbnail_size: 256,
+            annotated_size: 800,
+        }
+    }
+}
+
+// Occlusion-sensitivity explanations, and the compute budget a request may spend on them.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Explain {
+    // Side of the occluding patch, in pixels of the model input
+    pub patch_size: u32,
+    // Distance between two positions of the patch, in pixels of the model input
+    pub stride: u32,
+    // Most occluded images a request may run through the model; the stride is widened to fit
+    pub max_evaluations: usize,
+}
+
+impl Default for Explain {
+    fn default() -> Self {
+        Explain {
+            patch_size: 32,
+            stride: 16,
+            max_evaluations: 256,
+        }
+    }
+}
+
+// The translations of the web pages and labels.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Locales {
+    // Directory of "<language tag>.toml" files, relative to the preopened directory
+    pub dir: String,
+}
+
+impl Default for Locales {
+    fn default() -> Self {
+        Locales {
+            dir: "locales".to_string(),
+        }
+    }
+}
+
+// The stylesheet, scripts and icons served under /static/.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Assets {
+    // Directory of files overriding the embedded assets of the same names, relative to the
+    // preopened directory. They are read on every request, to edit them without a rebuild.
+    pub dir: Option<String>,
+}
+
+// Access to the administration routes, which browse the history, export the stored uploads, clear
+// the cache and report on the rollout.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    // Bearer token that requests must send in their Authorization header. The administration
+    // routes are not served when it is not set.
+    pub token: Option<String>,
+}
+
+// The out-of-distribution check, which flags images that look like none of the classes instead of
+// confidently reporting a wrong one.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenSet {
+    pub enabled: bool,
+    pub method: OpenSetMethod,
+    // Score past which an image is unknown, the default of the method when unset
+    pub unknown: Option<f32>,
+    // Score past which the predictions are of low confidence, the default of the method when unset
+    pub low_confidence: Option<f32>,
+}
+
+impl Default for OpenSet {
+    fn default() -> Self {
+        OpenSet {
+            enabled: true,
+            method: OpenSetMethod::MaxSoftmax,
+            unknown: None,
+            low_confidence: None,
+        }
+    }
+}
+
+// How confident a model is that an image belongs to one of its classes.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenSetMethod {
+    // Highest class probability, low when the image is unknown
+    MaxSoftmax,
+    // Entropy of the class probabilities, scaled to [0, 1], high when the image is unknown
+    Entropy,
+    // Energy of the logits, the negated log-sum-exp, high when the image is unknown
+    Energy,
+}
+
+// A rollout of a candidate model or ensemble, which serves part of the requests that do not pick a
+// model instead of the primary one.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rollout {
+    // Name of the model or ensemble rolled out, no rollout when it is not set
+    pub candidate: Option<String>,
+    // Name of the model or ensemble it is rolled out against, the default model when it is not set
+    pub primary: Option<String>,
+    // Percentage of the clients served by the candidate, or whose images it classifies in shadow
+    // mode
+    pub percent: f32,
+    // Only classify images with the candidate to compare it with the primary, never serving its
+    // results
+    pub shadow: bool,
+    // Fraction of the requests of the share of the candidate that it classifies in shadow mode.
+    // Every shadow run holds up the server while the candidate classifies, so a fraction below 1
+    // bounds the load a slow candidate adds to live traffic
+    pub shadow_sample: f32,
+    // Request header pinning a request to the "primary" or the "candidate" variant
+    pub header: String,
+    // Variants that clients are pinned to by the API key of their X-API-Key header
+    pub api_keys: BTreeMap<String, Variant>,
+}
+
+impl Default for Rollout {
+    fn default() -> Self {
+        Rollout {
+            candidate: None,
+            primary: None,
+            percent: 0.,
+            shadow: false,
+            shadow_sample: 1.,
+            header: "x-model-variant".to_string(),
+            api_keys: BTreeMap::new(),
+        }
+    }
+}
+
+// The two variants of a rollout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    Primary,
+    Candidate,
+}
+
+// A model served in addition to the embedded MobileNet model.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    pub name: String,
+    // Model files passed to the backend, e.g. a single .pt file, or the .xml and .bin files of
+    // an OpenVINO model
+    pub files: Vec<String>,
+    #[serde(default = "default_encoding")]
+    pub encoding: ModelEncoding,
+    #[serde(default)]
+    pub target: ModelTarget,
+    #[serde(default = "default_input_size")]
+    pub input_width: u32,
+    #[serde(default = "default_input_size")]
+    pub input_height: u32,
+    // Number of scores in the output tensor, one per class of the label space
+    #[serde(default = "default_classes")]
+    pub classes: usize,
+    #[serde(default)]
+    pub output: ModelOutput,
+    // Name of the label map applied to the results of the model by default
+    pub label_map: Option<String>,
+}
+
+fn default_encoding() -> ModelEncoding {
+    ModelEncoding::Pytorch
+}
+
+fn default_input_size() -> u32 {
+    224
+}
+
+fn default_classes() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelEncoding {
+    Openvino,
+    Onnx,
+    Tensorflow,
+    Pytorch,
+    Tensorflowlite,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelTarget {
+    #[default]
+    Cpu,
+    Gpu,
+    Tpu,
+}
+
+// What the scores in the output tensor of a model are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelOutput {
+    // Unnormalized scores, turned into probabilities with a softmax before they are reported
+    #[default]
+    Logits,
+    Probabilities,
+}
+
+// A virtual model that combines the scores of several models sharing the same label space.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnsembleConfig {
+    pub name: String,
+    pub members: Vec<EnsembleMemberConfig>,
+    #[serde(default)]
+    pub combine: CombineRule,
+    // Name of the label map applied to the results of the ensemble by default
+    pub label_map: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnsembleMemberConfig {
+    pub model: String,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.
+}
+
+// How the class probabilities of the members of an ensemble are combined.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CombineRule {
+    // Weighted mean of the probabilities
+    #[default]
+    Mean,
+    // Highest probability given to the class by any member (weights are ignored), normalized
+    Max,
+    // Weighted Borda count over the rankings of the members, turned into probabilities with a
+    // softmax. Results are not checked for unknown images, since the counts do not reflect the
+    // confidence of the members
+    RankVote,
+}
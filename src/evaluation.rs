@@ -0,0 +1,366 @@
+use crate::inference::labels::NO_LABEL_MAP;
+use crate::inference::{self, Classification, InferenceOptions};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// An image of a dataset with the class it is labelled with.
+#[derive(Debug)]
+pub struct Example {
+    pub path: PathBuf,
+    pub class_id: usize,
+}
+
+// Load an ImageFolder-style dataset: every subdirectory of 'dir' is named after a label and
+// holds the images of that label.
+pub fn load_image_folder(dir: &Path) -> Result<Vec<Example>, String> {
+    let mut examples = Vec::new();
+    for label_dir in sorted_entries(dir)? {
+        if !label_dir.is_dir() {
+            continue;
+        }
+        let label = label_dir.file_name().unwrap_or_default().to_string_lossy();
+        let class_id = find_class(&label)?;
+        for path in sorted_entries(&label_dir)? {
+            if path.is_file() && image::ImageFormat::from_path(&path).is_ok() {
+                examples.push(Example { path, class_id });
+            }
+        }
+    }
+    Ok(examples)
+}
+
+// Load a dataset from a CSV manifest with a path and a label on each line. Relative paths are
+// resolved against the directory of the manifest, and a "path,label" header line is skipped.
+pub fn load_manifest(manifest: &Path) -> Result<Vec<Example>, String> {
+    let contents = fs::read_to_string(manifest)
+        .map_err(|e| format!("failed to read {}: {}", manifest.display(), e))?;
+    let base = manifest.parent().unwrap_or_else(|| Path::new(""));
+    let mut examples = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_csv_line(line);
+        if number == 0 && fields == ["path", "label"] {
+            continue;
+        }
+        if fields.len() != 2 {
+            return Err(format!(
+                "{}:{}: expected a path and a label",
+                manifest.display(),
+                number + 1
+            ));
+        }
+        examples.push(Example {
+            path: base.join(&fields[0]),
+            class_id: find_class(&fields[1])?,
+        });
+    }
+    Ok(examples)
+}
+
+fn find_class(label: &str) -> Result<usize, String> {
+    inference::find_class(label).ok_or_else(|| format!("unknown label: {}", label))
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("failed to read {}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    paths.sort();
+    Ok(paths)
+}
+
+// Split a CSV line into its fields, unquoting quoted fields.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.iter().map(|f| f.trim().to_string()).collect()
+}
+
+// The results of evaluating a model on a dataset.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub model: String,
+    // Number of images that were classified
+    pub examples: usize,
+    pub top1_accuracy: f32,
+    pub top5_accuracy: f32,
+    pub classes: Vec<ClassMetrics>,
+    pub confusion_matrix: ConfusionMatrix,
+    // The misclassified images the model was most confident about
+    pub worst_misclassified: Vec<Misclassification>,
+    // Images that could not be classified, with their error
+    pub failures: Vec<Failure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClassMetrics {
+    pub class_id: usize,
+    pub label: String,
+    // Number of images labelled with the class
+    pub support: usize,
+    // Undefined (null) when the class was never predicted
+    pub precision: Option<f32>,
+    // Undefined (null) when no image is labelled with the class
+    pub recall: Option<f32>,
+}
+
+// Counts of the top-1 predictions (columns) for the images of each label (rows), over the
+// classes that were either labelled or predicted.
+#[derive(Debug, Serialize)]
+pub struct ConfusionMatrix {
+    pub labels: Vec<String>,
+    pub counts: Vec<Vec<usize>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Misclassification {
+    pub path: String,
+    pub expected: String,
+    pub predicted: String,
+    pub score: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    pub path: String,
+    pub error: String,
+}
+
+// Classify every example of a dataset and compute the metrics of the model on it. Datasets are
+// labelled with ImageNet classes, so the results are never remapped by a label map, whether the
+// options name one or the model has one by default, and at least five predictions are kept for
+// the top-5 accuracy.
+pub fn evaluate(examples: &[Example], options: &InferenceOptions, worst_count: usize) -> Report {
+    let options = InferenceOptions {
+        label_map: Some(NO_LABEL_MAP.to_string()),
+        top_k: Some(options.top_k.unwrap_or(5).max(5)),
+        ..options.clone()
+    };
+    let mut results: Vec<(&Example, Classification)> = Vec::new();
+    let mut failures = Vec::new();
+    for (i, example) in examples.iter().enumerate() {
+        eprintln!("[{}/{}] {}", i + 1, examples.len(), example.path.display());
+        let failure = |error: String| Failure {
+            path: example.path.display().to_string(),
+            error,
+        };
+        match inference::infer_image(&example.path.to_string_lossy(), &options) {
+            Ok(classification) if classification.predictions.is_empty() => {
+                failures.push(failure("no prediction".to_string()))
+            }
+            Ok(classification) => results.push((example, classification)),
+            Err(e) => failures.push(failure(e.to_string())),
+        }
+    }
+    let model = match results.first() {
+        Some((_, classification)) => classification.model.clone(),
+        None => options.model.clone().unwrap_or_default(),
+    };
+    report(model, &results, failures, worst_count)
+}
+
+// Compute the metrics of a model from the classifications of the examples of a dataset, keeping
+// the worst_count misclassified images the model was most confident about.
+fn report(
+    model: String,
+    results: &[(&Example, Classification)],
+    failures: Vec<Failure>,
+    worst_count: usize,
+) -> Report {
+    let mut top1 = 0;
+    let mut top5 = 0;
+    // Per class: images labelled with it, images predicted as it, and correct predictions
+    let mut labelled: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut predicted: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut correct: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut confusion: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+    let mut misclassified = Vec::new();
+    for (example, classification) in results {
+        let top = &classification.predictions[0];
+        *labelled.entry(example.class_id).or_default() += 1;
+        *predicted.entry(top.class_id).or_default() += 1;
+        *confusion
+            .entry((example.class_id, top.class_id))
+            .or_default() += 1;
+        if top.class_id == example.class_id {
+            top1 += 1;
+            *correct.entry(example.class_id).or_default() += 1;
+        } else {
+            misclassified.push(Misclassification {
+                path: example.path.display().to_string(),
+                expected: inference::label(example.class_id),
+                predicted: top.label.clone(),
+                score: top.probability,
+            });
+        }
+        if classification
+            .predictions
+            .iter()
+            .take(5)
+            .any(|p| p.class_id == example.class_id)
+        {
+            top5 += 1;
+        }
+    }
+    misclassified.sort_by(|a, b| b.score.total_cmp(&a.score));
+    misclassified.truncate(worst_count);
+
+    let class_ids: BTreeSet<usize> = labelled.keys().chain(predicted.keys()).cloned().collect();
+    let ratio = |numerator: usize, denominator: usize| {
+        (denominator > 0).then(|| numerator as f32 / denominator as f32)
+    };
+    let classes = class_ids
+        .iter()
+        .map(|class_id| {
+            let correct = correct.get(class_id).cloned().unwrap_or(0);
+            let support = labelled.get(class_id).cloned().unwrap_or(0);
+            ClassMetrics {
+                class_id: *class_id,
+                label: inference::label(*class_id),
+                support,
+                precision: ratio(correct, predicted.get(class_id).cloned().unwrap_or(0)),
+                recall: ratio(correct, support),
+            }
+        })
+        .collect();
+    let confusion_matrix = ConfusionMatrix {
+        labels: class_ids.iter().map(|c| inference::label(*c)).collect(),
+        counts: class_ids
+            .iter()
+            .map(|expected| {
+                class_ids
+                    .iter()
+                    .map(|actual| confusion.get(&(*expected, *actual)).cloned().unwrap_or(0))
+                    .collect()
+            })
+            .collect(),
+    };
+
+    Report {
+        model,
+        examples: results.len(),
+        top1_accuracy: ratio(top1, results.len()).unwrap_or(0.),
+        top5_accuracy: ratio(top5, results.len()).unwrap_or(0.),
+        classes,
+        confusion_matrix,
+        worst_misclassified: misclassified,
+        failures,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(class_id: usize) -> Example {
+        Example {
+            path: PathBuf::from(format!("{}.jpg", class_id)),
+            class_id,
+        }
+    }
+
+    // A classification with the given classes as predictions, the first one with the given score.
+    fn classification(classes: &[usize], score: f32) -> Classification {
+        let predictions: Vec<_> = classes
+            .iter()
+            .enumerate()
+            .map(|(i, class_id)| {
+                serde_json::json!({
+                    "class_id": class_id,
+                    "label": inference::label(*class_id),
+                    "probability": if i == 0 { score } else { 0.01 },
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({ "model": "test", "predictions": predictions }))
+            .unwrap()
+    }
+
+    fn report_of(results: &[(&Example, Classification)], worst_count: usize) -> Report {
+        report("test".to_string(), results, Vec::new(), worst_count)
+    }
+
+    #[test]
+    fn computes_the_accuracy_and_the_metrics_of_each_class() {
+        let examples = [example(0), example(0), example(1), example(2)];
+        let results = [
+            (&examples[0], classification(&[0, 1], 0.8)),
+            (&examples[1], classification(&[1, 0], 0.6)),
+            (&examples[2], classification(&[1], 0.7)),
+            (&examples[3], classification(&[3, 0], 0.9)),
+        ];
+        let report = report_of(&results, 10);
+        assert_eq!(report.examples, 4);
+        assert_eq!(report.top1_accuracy, 0.5);
+        assert_eq!(report.top5_accuracy, 0.75);
+
+        let metrics: Vec<_> = report
+            .classes
+            .iter()
+            .map(|c| (c.class_id, c.support, c.precision, c.recall))
+            .collect();
+        assert_eq!(
+            metrics,
+            [
+                (0, 2, Some(1.), Some(0.5)),
+                (1, 1, Some(0.5), Some(1.)),
+                // Never predicted
+                (2, 1, None, Some(0.)),
+                // Never labelled
+                (3, 0, Some(0.), None),
+            ]
+        );
+        assert_eq!(
+            report.confusion_matrix.counts,
+            [[1, 1, 0, 0], [0, 1, 0, 0], [0, 0, 0, 1], [0, 0, 0, 0]]
+        );
+        assert_eq!(report.confusion_matrix.labels[3], inference::label(3));
+
+        let worst: Vec<_> = report
+            .worst_misclassified
+            .iter()
+            .map(|m| (m.path.as_str(), m.score))
+            .collect();
+        assert_eq!(worst, [("2.jpg", 0.9), ("0.jpg", 0.6)]);
+        let report = report_of(&results, 1);
+        assert_eq!(report.worst_misclassified.len(), 1);
+    }
+
+    #[test]
+    fn counts_only_the_five_first_predictions_for_the_top_5_accuracy() {
+        let examples = [example(4), example(5)];
+        let results = [
+            (&examples[0], classification(&[0, 1, 2, 3, 4, 5], 0.5)),
+            (&examples[1], classification(&[0, 1, 2, 3, 4, 5], 0.5)),
+        ];
+        let report = report_of(&results, 0);
+        assert_eq!(report.top1_accuracy, 0.);
+        assert_eq!(report.top5_accuracy, 0.5);
+        assert!(report.worst_misclassified.is_empty());
+    }
+
+    #[test]
+    fn reports_no_accuracy_without_results() {
+        let report = report_of(&[], 10);
+        assert_eq!(report.examples, 0);
+        assert_eq!(report.top1_accuracy, 0.);
+        assert!(report.classes.is_empty());
+        assert!(report.confusion_matrix.counts.is_empty());
+    }
+}
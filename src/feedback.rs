@@ -0,0 +1,327 @@
+use crate::history::{self, empty_as_none, HistoryError};
+use crate::inference;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Suffix of the feedback file stored next to each image, e.g. "<image ID>.feedback.jsonl"
+const FEEDBACK_SUFFIX: &str = ".feedback.jsonl";
+
+// Prefix of the image hashed into its feedback token, so that the token is not its ID
+const TOKEN_PREFIX: &[u8] = b"feedback:";
+
+// Feedback sent by a user on the classification of an image.
+#[derive(Debug, Deserialize)]
+pub struct Feedback {
+    // The image ID returned with the classification
+    pub image_id: String,
+    // The feedback token returned with the classification
+    pub token: String,
+    // Whether the top prediction was right
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub verdict: Option<Verdict>,
+    // The correct class, as an ImageNet class ID or name
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub class: Option<String>,
+    // A free-text label, for images that are of none of the classes
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Up,
+    Down,
+}
+
+impl std::str::FromStr for Verdict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        inference::parse_option(s)
+    }
+}
+
+// Feedback as it is stored, along with the prediction it was given on.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeedbackRecord {
+    pub timestamp: u64,
+    pub time: String,
+    pub image_id: String,
+    // Model and top label of the latest classification of the image
+    pub model: Option<String>,
+    pub predicted: Option<String>,
+    pub verdict: Option<Verdict>,
+    pub class_id: Option<usize>,
+    pub class_label: Option<String>,
+    pub label: Option<String>,
+}
+
+impl FeedbackRecord {
+    // The label an image is known to have according to this feedback: the correction if there is
+    // one, or the prediction the user agreed with.
+    fn known_label(&self) -> Option<&str> {
+        self.class_label
+            .as_deref()
+            .or(self.label.as_deref())
+            .or(match self.verdict {
+                Some(Verdict::Up) => self.predicted.as_deref(),
+                _ => None,
+            })
+    }
+}
+
+#[derive(Debug)]
+pub enum FeedbackError {
+    InvalidFeedback(String),
+    UnknownImage(String),
+    // The token does not match the image
+    InvalidToken,
+    Storage(String),
+}
+
+impl fmt::Display for FeedbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeedbackError::InvalidFeedback(msg) => write!(f, "invalid feedback: {}", msg),
+            FeedbackError::UnknownImage(id) => write!(f, "unknown image: {}", id),
+            FeedbackError::InvalidToken => write!(f, "invalid feedback token"),
+            FeedbackError::Storage(msg) => write!(f, "feedback storage error: {}", msg),
+        }
+    }
+}
+
+impl From<HistoryError> for FeedbackError {
+    fn from(e: HistoryError) -> Self {
+        FeedbackError::Storage(e.to_string())
+    }
+}
+
+fn storage_error(path: &Path, e: io::Error) -> FeedbackError {
+    FeedbackError::Storage(format!("{}: {}", path.display(), e))
+}
+
+// Path of the feedback file of a stored image, which sits next to the image.
+fn feedback_path(image: &Path, image_id: &str) -> PathBuf {
+    image.with_file_name(format!("{}{}", image_id, FEEDBACK_SUFFIX))
+}
+
+// The token that feedback on an image must carry, returned with its classification. It is a hash
+// of the image, so that only the clients that sent the image can label it, and not anyone who
+// learns its ID.
+pub fn token(image: &[u8]) -> String {
+    inference::content_hash(&[TOKEN_PREFIX, image].concat())
+}
+
+// Check feedback on a stored image and append it to the feedback file of the image.
+pub fn submit(feedback: &Feedback) -> Result<FeedbackRecord, FeedbackError> {
+    if feedback.verdict.is_none() && feedback.class.is_none() && feedback.label.is_none() {
+        return Err(FeedbackError::InvalidFeedback(
+            "expected a verdict, a class or a label".to_string(),
+        ));
+    }
+    let image = history::image_path(&feedback.image_id)
+        .ok_or_else(|| FeedbackError::UnknownImage(feedback.image_id.clone()))?;
+    let data = fs::read(&image).map_err(|e| storage_error(&image, e))?;
+    if !inference::same_token(&token(&data), &feedback.token) {
+        return Err(FeedbackError::InvalidToken);
+    }
+    let class_id =
+        match &feedback.class {
+            Some(class) => Some(inference::find_class(class).ok_or_else(|| {
+                FeedbackError::InvalidFeedback(format!("unknown class: {}", class))
+            })?),
+            None => None,
+        };
+    let latest = history::latest_record(&feedback.image_id)?;
+
+    let timestamp = history::now();
+    let record = FeedbackRecord {
+        timestamp,
+        time: history::format_time(timestamp),
+        image_id: feedback.image_id.clone(),
+        model: latest.as_ref().map(|r| r.model.clone()),
+        predicted: latest
+            .as_ref()
+            .and_then(|r| r.predictions.first())
+            .map(|p| p.label.clone()),
+        verdict: feedback.verdict,
+        class_id,
+        class_label: class_id.map(inference::label),
+        label: feedback.label.as_ref().map(|l| l.trim().to_string()),
+    };
+    let mut line =
+        serde_json::to_string(&record).map_err(|e| FeedbackError::Storage(e.to_string()))?;
+    line.push('\n');
+    let path = feedback_path(&image, &feedback.image_id);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| storage_error(&path, e))?;
+    Ok(record)
+}
+
+// Export the images with a known label as an ImageFolder-style dataset, in a tar archive with
+// one directory per label. The latest feedback on an image wins; images whose prediction was
+// only marked as wrong, without a correction, are left out.
+pub fn export() -> Result<Vec<u8>, FeedbackError> {
+    let mut labelled: BTreeMap<String, (String, PathBuf)> = BTreeMap::new();
+    for path in feedback_files()? {
+        let contents = fs::read_to_string(&path).map_err(|e| storage_error(&path, e))?;
+        let latest = contents
+            .lines()
+            .rev()
+            .find_map(|line| serde_json::from_str::<FeedbackRecord>(line).ok());
+        let Some(feedback) = latest else { continue };
+        let Some(label) = feedback.known_label() else {
+            continue;
+        };
+        if let Some(image) = history::image_path(&feedback.image_id) {
+            labelled.insert(feedback.image_id.clone(), (directory_name(label), image));
+        }
+    }
+
+    let mut archive = Vec::new();
+    for (image_id, (label, image)) in &labelled {
+        let data = fs::read(image).map_err(|e| storage_error(image, e))?;
+        let extension = image.extension().unwrap_or_default().to_string_lossy();
+        append_tar_entry(
+            &mut archive,
+            label,
+            &format!("{}.{}", image_id, extension),
+            &data,
+        );
+    }
+    // An archive ends with two empty blocks
+    archive.extend_from_slice(&[0; 1024]);
+    Ok(archive)
+}
+
+// The feedback files of every stored image.
+fn feedback_files() -> Result<Vec<PathBuf>, FeedbackError> {
+    let dir = history::images_dir();
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(storage_error(&dir, e)),
+    };
+    Ok(entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.to_string_lossy().ends_with(FEEDBACK_SUFFIX))
+        .collect())
+}
+
+// Turn a label into a directory name that the evaluate binary reads back as the same label:
+// spaces become underscores, characters that are unsafe in paths are dropped, and long labels
+// are cut to 64 bytes.
+fn directory_name(label: &str) -> String {
+    let mut name: String = label
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, '/' | '\\' | '\0' | ':'))
+        .map(|c| if c == ' ' { '_' } else { c })
+        .collect();
+    let mut end = name.len().min(64);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name.truncate(end);
+    match name.trim_matches('.') {
+        "" => "unlabelled".to_string(),
+        _ => name,
+    }
+}
+
+// Append a file to a tar archive in the ustar format, with its directory in the prefix of its
+// name. Names must fit in the 100 bytes of the header and directories in the 155 bytes of the
+// prefix, which the image IDs and directory names of the export always do.
+fn append_tar_entry(archive: &mut Vec<u8>, dir: &str, name: &str, data: &[u8]) {
+    let mut header = [0u8; 512];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, &name.as_bytes()[..name.len().min(100)]);
+    field(345, &dir.as_bytes()[..dir.len().min(155)]);
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", data.len()).as_bytes());
+    field(136, format!("{:011o}\0", history::now()).as_bytes());
+    // The checksum is computed with its own field filled with spaces
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    // Pad the data to a whole number of blocks
+    archive.resize(archive.len().div_ceil(512) * 512, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turns_labels_into_directory_names() {
+        assert_eq!(directory_name(" Granny Smith "), "Granny_Smith");
+        assert_eq!(directory_name("../etc/passwd"), "..etcpasswd");
+        assert_eq!(directory_name("C:\\dir\\label"), "Cdirlabel");
+        assert_eq!(directory_name(".."), "unlabelled");
+        assert_eq!(directory_name(" / "), "unlabelled");
+        // Long labels are cut on a character boundary
+        let name = directory_name(&"é".repeat(40));
+        assert_eq!(name, "é".repeat(32));
+    }
+
+    #[test]
+    fn derives_tokens_from_the_image() {
+        assert_eq!(token(b"image"), token(b"image"));
+        assert_ne!(token(b"image"), token(b"other image"));
+        assert_ne!(token(b"image"), inference::content_hash(b"image"));
+    }
+
+    #[test]
+    fn writes_ustar_entries() {
+        let image_id = "a".repeat(64);
+        let name = format!("{}.jpeg", image_id);
+        let mut archive = Vec::new();
+        append_tar_entry(&mut archive, "Granny_Smith", &name, b"data");
+        // One header block and one block of padded data
+        assert_eq!(archive.len(), 1024);
+        let header = &archive[..512];
+        assert_eq!(&header[..name.len()], name.as_bytes());
+        assert_eq!(header[name.len()], 0);
+        assert_eq!(&header[345..357], b"Granny_Smith");
+        assert_eq!(&header[124..136], b"00000000004\0");
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..263], b"ustar\0");
+        assert_eq!(&archive[512..516], b"data");
+        assert!(archive[516..].iter().all(|b| *b == 0));
+
+        // The checksum is the sum of the header, with the checksum field as spaces
+        let checksum = std::str::from_utf8(&header[148..154]).unwrap();
+        let sum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    *b as u32
+                }
+            })
+            .sum();
+        assert_eq!(u32::from_str_radix(checksum, 8).unwrap(), sum);
+    }
+}
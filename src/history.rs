@@ -0,0 +1,506 @@
+use crate::config::{Variant, CONFIG};
+use crate::inference::{self, Classification, Prediction};
+use image::ImageFormat;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Files of the store, inside the configured history directory
+const RECORDS_FILE: &str = "records.jsonl";
+const IMAGES_DIR: &str = "images";
+
+// File extensions of the stored images, by format
+const IMAGE_EXTENSIONS: [(ImageFormat, &str); 6] = [
+    (ImageFormat::Jpeg, "jpg"),
+    (ImageFormat::Png, "png"),
+    (ImageFormat::Gif, "gif"),
+    (ImageFormat::WebP, "webp"),
+    (ImageFormat::Bmp, "bmp"),
+    (ImageFormat::Tiff, "tiff"),
+];
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// A classification served by the web server. Records are appended to the store as JSON lines
+// and never rewritten.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Record {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    // The same instant in UTC, formatted as RFC 3339 for people reading the store
+    pub time: String,
+    // Content hash of the classified image, which also names its stored copy and its renditions
+    pub image_id: String,
+    pub model: String,
+    pub predictions: Vec<Prediction>,
+    pub latency_ms: f64,
+    // Address of the client, when the server knows it
+    pub client: Option<String>,
+    // The variant of the rollout that classified the image, when one was configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<Variant>,
+    // Whether the candidate of the rollout classified the image in the shadow of the primary,
+    // without its results being returned
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shadow: bool,
+}
+
+// Filters and page of a history request. Dates are "YYYY-MM-DD" days in UTC, both included, or
+// Unix timestamps in seconds.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryQuery {
+    // Part of the label of the top prediction, ignoring case
+    #[serde(deserialize_with = "empty_as_none")]
+    pub label: Option<String>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub model: Option<String>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub variant: Option<Variant>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub from: Option<String>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub to: Option<String>,
+    // Lowest score of the top prediction
+    #[serde(deserialize_with = "empty_as_none")]
+    pub min_confidence: Option<f32>,
+    // Page number, starting at 1
+    #[serde(deserialize_with = "empty_as_none")]
+    pub page: Option<usize>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub per_page: Option<usize>,
+}
+
+// Read an empty query parameter as a missing one, since HTML forms submit their empty fields.
+pub(crate) fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(de::Error::custom),
+    }
+}
+
+// A page of matching records, newest first.
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    pub page: usize,
+    pub per_page: usize,
+    pub pages: usize,
+    // Number of records matching the filters, over every page
+    pub total: usize,
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    InvalidQuery(String),
+    Storage(String),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::InvalidQuery(msg) => write!(f, "invalid history query: {}", msg),
+            HistoryError::Storage(msg) => write!(f, "history storage error: {}", msg),
+        }
+    }
+}
+
+fn storage_error(path: &Path, e: io::Error) -> HistoryError {
+    HistoryError::Storage(format!("{}: {}", path.display(), e))
+}
+
+// Identify an image by the hash of its bytes.
+pub fn image_id(buf: &[u8]) -> String {
+    inference::content_hash(buf)
+}
+
+// Record a classification served to a client, or run in the shadow of one, along with its image.
+// Does nothing when the history is disabled.
+pub fn record(
+    image: &[u8],
+    classification: &Classification,
+    latency: Duration,
+    client: Option<SocketAddr>,
+    variant: Option<Variant>,
+    shadow: bool,
+) -> Result<(), HistoryError> {
+    if !CONFIG.history.enabled {
+        return Ok(());
+    }
+    let dir = Path::new(&CONFIG.history.dir);
+    let images = images_dir();
+    fs::create_dir_all(&images).map_err(|e| storage_error(&images, e))?;
+
+    let image_id = image_id(image);
+    // The same image is often classified more than once, it is only stored once
+    if image_path(&image_id).is_none() {
+        let extension = image::guess_format(image)
+            .ok()
+            .and_then(|format| IMAGE_EXTENSIONS.iter().find(|(f, _)| *f == format))
+            .map_or("bin", |(_, extension)| extension);
+        let path = images.join(format!("{}.{}", image_id, extension));
+        fs::write(&path, image).map_err(|e| storage_error(&path, e))?;
+    }
+
+    let timestamp = now();
+    let record = Record {
+        timestamp,
+        time: format_time(timestamp),
+        image_id,
+        model: classification.model.clone(),
+        predictions: classification.predictions.clone(),
+        latency_ms: latency.as_secs_f64() * 1000.,
+        client: client.map(|addr| addr.ip().to_string()),
+        variant,
+        shadow,
+    };
+    let mut line =
+        serde_json::to_string(&record).map_err(|e| HistoryError::Storage(e.to_string()))?;
+    line.push('\n');
+    let records = dir.join(RECORDS_FILE);
+    // Write the record with a single call, so that a record is never interleaved with another
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&records)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| storage_error(&records, e))
+}
+
+// Find the records matching the filters of a query and return the requested page of them.
+pub fn query(query: &HistoryQuery) -> Result<HistoryPage, HistoryError> {
+    select(read_records()?, query)
+}
+
+// Filter records, oldest first, and return the requested page of the matching ones.
+fn select(records: Vec<Record>, query: &HistoryQuery) -> Result<HistoryPage, HistoryError> {
+    let from = query
+        .from
+        .as_deref()
+        .map(|d| parse_date(d, false))
+        .transpose()?;
+    let to = query
+        .to
+        .as_deref()
+        .map(|d| parse_date(d, true))
+        .transpose()?;
+    let label = query.label.as_ref().map(|l| l.to_lowercase());
+    let per_page = query.per_page.unwrap_or(CONFIG.history.page_size).max(1);
+    let page = query.page.unwrap_or(1).max(1);
+
+    let mut matching: Vec<Record> = records
+        .into_iter()
+        .filter(|record| {
+            let top = record.predictions.first();
+            from.is_none_or(|from| record.timestamp >= from)
+                && to.is_none_or(|to| record.timestamp < to)
+                && query
+                    .model
+                    .as_ref()
+                    .is_none_or(|model| &record.model == model)
+                && query
+                    .variant
+                    .is_none_or(|variant| record.variant == Some(variant))
+                && label
+                    .as_ref()
+                    .is_none_or(|label| top.is_some_and(|p| p.label.to_lowercase().contains(label)))
+                && query
+                    .min_confidence
+                    .is_none_or(|min| top.is_some_and(|p| p.probability >= min))
+        })
+        .collect();
+    matching.reverse();
+
+    let total = matching.len();
+    Ok(HistoryPage {
+        page,
+        per_page,
+        pages: total.div_ceil(per_page).max(1),
+        total,
+        records: matching
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect(),
+    })
+}
+
+// The latest record of an image that was served to a client, if it was ever classified.
+pub(crate) fn latest_record(image_id: &str) -> Result<Option<Record>, HistoryError> {
+    Ok(read_records()?
+        .into_iter()
+        .rev()
+        .find(|record| record.image_id == image_id && !record.shadow))
+}
+
+// Read every record of the store, oldest first. A line that cannot be parsed, e.g. one cut short
+// by a crash, is skipped rather than making the whole history unreadable.
+fn read_records() -> Result<Vec<Record>, HistoryError> {
+    let path = Path::new(&CONFIG.history.dir).join(RECORDS_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(storage_error(&path, e)),
+    };
+    Ok(contents
+        .lines()
+        .enumerate()
+        .filter_map(|(number, line)| match serde_json::from_str(line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                eprintln!("Skipping history record {}: {}", number + 1, e);
+                None
+            }
+        })
+        .collect())
+}
+
+// Check that an image ID is well formed, so that a request cannot name files outside of the
+// store.
+pub(crate) fn valid_image_id(image_id: &str) -> bool {
+    image_id.len() == 64 && image_id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Directory of the stored images.
+pub(crate) fn images_dir() -> PathBuf {
+    Path::new(&CONFIG.history.dir).join(IMAGES_DIR)
+}
+
+// Path of a stored image, if the ID is well formed and the image exists.
+pub fn image_path(image_id: &str) -> Option<PathBuf> {
+    if !valid_image_id(image_id) {
+        return None;
+    }
+    let images = images_dir();
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|(_, extension)| *extension)
+        .chain(["bin"])
+        .map(|extension| images.join(format!("{}.{}", image_id, extension)))
+        .find(|path| path.is_file())
+}
+
+// Seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Parse a date filter into a Unix timestamp. A day starts at midnight UTC; the end of a range is
+// exclusive, so a day given as the end of a range includes the whole day.
+fn parse_date(value: &str, end: bool) -> Result<u64, HistoryError> {
+    let invalid = || HistoryError::InvalidQuery(format!("invalid date: {}", value));
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return timestamp.checked_add(end as u64).ok_or_else(invalid);
+    }
+    let parts: Vec<&str> = value.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let (year, month, day) = (
+        year.parse::<i64>().map_err(|_| invalid())?,
+        month.parse::<i64>().map_err(|_| invalid())?,
+        day.parse::<i64>().map_err(|_| invalid())?,
+    );
+    // Four-digit years keep the date arithmetic far from overflowing
+    if !(0..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+    {
+        return Err(invalid());
+    }
+    let days = days_from_civil(year, month, day) + end as i64;
+    Ok((days * SECONDS_PER_DAY).max(0) as u64)
+}
+
+// Number of days in a month of the proleptic Gregorian calendar.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Format a Unix timestamp as an RFC 3339 time in UTC.
+pub(crate) fn format_time(timestamp: u64) -> String {
+    let days = (timestamp / SECONDS_PER_DAY as u64) as i64;
+    let seconds = timestamp % SECONDS_PER_DAY as u64;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Convert between days since the Unix epoch and proleptic Gregorian dates, following Howard
+// Hinnant's date algorithms.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = (month_index + 2) % 12 + 1;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_days_and_timestamps() {
+        assert_eq!(parse_date("1970-01-01", false).unwrap(), 0);
+        assert_eq!(parse_date("1970-01-01", true).unwrap(), 86400);
+        assert_eq!(parse_date("2024-02-29", false).unwrap(), 19782 * 86400);
+        assert_eq!(parse_date("2000-02-29", false).unwrap(), 11016 * 86400);
+        assert_eq!(parse_date("1700000000", false).unwrap(), 1700000000);
+        assert_eq!(parse_date("1700000000", true).unwrap(), 1700000001);
+    }
+
+    #[test]
+    fn rejects_days_that_do_not_exist() {
+        for date in [
+            "2024-02-30",
+            "2024-02-31",
+            "2023-02-29",
+            "1900-02-29",
+            "2024-04-31",
+            "2024-11-31",
+            "2024-00-10",
+            "2024-13-01",
+            "2024-01-00",
+            "10000-01-01",
+            "2024-01",
+            "yesterday",
+        ] {
+            assert!(
+                matches!(parse_date(date, false), Err(HistoryError::InvalidQuery(_))),
+                "{}",
+                date
+            );
+        }
+    }
+
+    #[test]
+    fn converts_between_days_and_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2024, 3, 1), 19783);
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert!((1..=days_in_month(year, month)).contains(&day));
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(format_time(19782 * 86400 + 3723), "2024-02-29T01:02:03Z");
+    }
+
+    fn record(timestamp: u64, model: &str, label: &str, probability: f32) -> Record {
+        Record {
+            timestamp,
+            time: format_time(timestamp),
+            image_id: "0123456789abcdef".repeat(4),
+            model: model.to_string(),
+            predictions: vec![Prediction {
+                class_id: 0,
+                label: label.to_string(),
+                localized_label: None,
+                probability,
+            }],
+            latency_ms: 1.,
+            client: None,
+            variant: None,
+            shadow: false,
+        }
+    }
+
+    // The timestamps of the records matching a query, newest first.
+    fn matching(query: HistoryQuery) -> Vec<u64> {
+        let day = 86400;
+        let records = vec![
+            record(day - 1, "mobilenet", "banana", 0.9),
+            record(day, "mobilenet", "lemon", 0.4),
+            record(2 * day - 1, "resnet", "Banana", 0.6),
+            record(2 * day, "resnet", "orange", 0.8),
+        ];
+        let page = select(records, &query).unwrap();
+        page.records.iter().map(|r| r.timestamp).collect()
+    }
+
+    #[test]
+    fn filters_the_records() {
+        let day = 86400;
+        assert_eq!(
+            matching(HistoryQuery::default()),
+            [2 * day, 2 * day - 1, day, day - 1]
+        );
+        // The day given as the end of the range is included
+        let query = HistoryQuery {
+            from: Some("1970-01-02".to_string()),
+            to: Some("1970-01-02".to_string()),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(matching(query), [2 * day - 1, day]);
+        let query = HistoryQuery {
+            label: Some("BAN".to_string()),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(matching(query), [2 * day - 1, day - 1]);
+        let query = HistoryQuery {
+            model: Some("resnet".to_string()),
+            min_confidence: Some(0.7),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(matching(query), [2 * day]);
+    }
+
+    #[test]
+    fn pages_the_matching_records() {
+        let query = HistoryQuery {
+            page: Some(2),
+            per_page: Some(3),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(matching(query), [86400 - 1]);
+        let records = vec![record(1, "mobilenet", "banana", 0.9)];
+        let query = HistoryQuery {
+            per_page: Some(3),
+            ..HistoryQuery::default()
+        };
+        let page = select(records, &query).unwrap();
+        assert_eq!((page.page, page.pages, page.total), (1, 1, 1));
+    }
+}
@@ -0,0 +1,207 @@
+// Resized copies of the uploaded images: a thumbnail, and an annotated copy with the top label and
+// score of its classification drawn in a caption bar below the image.
+use crate::config::CONFIG;
+use crate::history;
+use crate::inference::explain::Explanation;
+use crate::inference::{self, Classification, Verdict};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+mod font;
+
+const CAPTION_BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+const CAPTION_TEXT: Rgb<u8> = Rgb([255, 255, 255]);
+
+// The copies made of every uploaded image, served as "<image ID>/<file name>".
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rendition {
+    Thumbnail,
+    // The copy annotated with a result, named by the ID of the result: the fingerprint of the
+    // models and options that produced it
+    Annotated(String),
+    // The heatmap of the latest explanation of a result, overlaid on the image and named by the ID
+    // of the result like the annotated copy
+    Explanation(String),
+}
+
+impl Rendition {
+    pub fn from_file_name(name: &str) -> Option<Rendition> {
+        if name == "thumbnail.png" {
+            return Some(Rendition::Thumbnail);
+        }
+        let result_id = |prefix: &str| {
+            name.strip_prefix(prefix)
+                .and_then(|name| name.strip_suffix(".png"))
+                .filter(|result_id| history::valid_image_id(result_id))
+                .map(str::to_string)
+        };
+        result_id("annotated-")
+            .map(Rendition::Annotated)
+            .or_else(|| result_id("explanation-").map(Rendition::Explanation))
+    }
+
+    pub fn file_name(&self) -> String {
+        match self {
+            Rendition::Thumbnail => "thumbnail.png".to_string(),
+            Rendition::Annotated(result_id) => format!("annotated-{}.png", result_id),
+            Rendition::Explanation(result_id) => format!("explanation-{}.png", result_id),
+        }
+    }
+}
+
+// Path of a copy of an image, stored as "<image ID>.<file name>".
+fn rendition_path(image_id: &str, rendition: &Rendition) -> PathBuf {
+    Path::new(&CONFIG.images.dir).join(format!("{}.{}", image_id, rendition.file_name()))
+}
+
+// Path of a copy of an image, if the IDs are well formed and the copy exists.
+pub fn path(image_id: &str, rendition: &Rendition) -> Option<PathBuf> {
+    let valid_result = match rendition {
+        Rendition::Annotated(result_id) | Rendition::Explanation(result_id) => {
+            history::valid_image_id(result_id)
+        }
+        Rendition::Thumbnail => true,
+    };
+    if !history::valid_image_id(image_id) || !valid_result {
+        return None;
+    }
+    let path = rendition_path(image_id, rendition);
+    path.is_file().then_some(path)
+}
+
+// Make the thumbnail of an uploaded image, shared by every page showing it, and its copy annotated
+// with a result. Both are only made once: the same result ID always comes with the same result.
+pub fn save(image: &[u8], result_id: &str, classification: &Classification) -> Result<(), String> {
+    let image_id = history::image_id(image);
+    let thumbnail = rendition_path(&image_id, &Rendition::Thumbnail);
+    let annotated = rendition_path(&image_id, &Rendition::Annotated(result_id.to_string()));
+    if thumbnail.exists() && annotated.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(&CONFIG.images.dir).map_err(|e| format!("{}: {}", CONFIG.images.dir, e))?;
+    let upright = inference::upright_image(image).map_err(|e| e.to_string())?;
+
+    if !thumbnail.exists() {
+        let size = CONFIG.images.thumbnail_size;
+        DynamicImage::ImageRgb8(upright.clone())
+            .thumbnail(size, size)
+            .save(&thumbnail)
+            .map_err(|e| format!("{}: {}", thumbnail.display(), e))?;
+    }
+
+    if annotated.exists() {
+        return Ok(());
+    }
+    let verdict = classification.open_set.as_ref().map(|check| check.verdict);
+    let caption = match (classification.predictions.first(), verdict) {
+        (Some(top), Some(Verdict::Unknown)) => {
+            format!("unknown (maybe {} {:.4})", top.label, top.probability)
+        }
+        (Some(top), Some(Verdict::LowConfidence)) => {
+            format!("{}? ({:.4}, low confidence)", top.label, top.probability)
+        }
+        (Some(top), _) => format!("{} ({:.4})", top.label, top.probability),
+        (None, _) => "no prediction".to_string(),
+    };
+    annotate(&upright, &caption)
+        .save(&annotated)
+        .map_err(|e| format!("{}: {}", annotated.display(), e))
+}
+
+// Overlay the heatmap of an explanation of a result on an image, scaled down like the annotated
+// copy, and save it. The hotter a pixel, the more opaque and the redder its color.
+pub fn save_explanation(
+    image: &[u8],
+    result_id: &str,
+    explanation: &Explanation,
+) -> Result<(), String> {
+    let image_id = history::image_id(image);
+    fs::create_dir_all(&CONFIG.images.dir).map_err(|e| format!("{}: {}", CONFIG.images.dir, e))?;
+    let upright = inference::upright_image(image).map_err(|e| e.to_string())?;
+    let mut img = scale_down(&upright, CONFIG.images.annotated_size);
+
+    let heatmap = GrayImage::from_fn(explanation.width, explanation.height, |x, y| {
+        let heat = explanation.heatmap[(y * explanation.width + x) as usize];
+        Luma([(heat * 255.).round() as u8])
+    });
+    let heatmap = imageops::resize(&heatmap, img.width(), img.height(), FilterType::Triangle);
+    for (pixel, heat) in img.pixels_mut().zip(heatmap.pixels()) {
+        let heat = heat[0] as f32 / 255.;
+        let color = heat_color(heat);
+        let alpha = 0.6 * heat;
+        for c in 0..3 {
+            pixel[c] = (pixel[c] as f32 * (1. - alpha) + color[c] as f32 * alpha).round() as u8;
+        }
+    }
+
+    let path = rendition_path(&image_id, &Rendition::Explanation(result_id.to_string()));
+    img.save(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Map a heat in [0, 1] to a color from blue through green and yellow to red.
+fn heat_color(heat: f32) -> [u8; 3] {
+    let channel = |center: f32| ((1.5 - (4. * heat - center).abs()).clamp(0., 1.) * 255.) as u8;
+    [channel(3.), channel(2.), channel(1.)]
+}
+
+// Scale an image down so that it fits in a square of the given size.
+fn scale_down(img: &RgbImage, size: u32) -> RgbImage {
+    if img.width() > size || img.height() > size {
+        DynamicImage::ImageRgb8(img.clone())
+            .resize(size, size, FilterType::Triangle)
+            .to_rgb8()
+    } else {
+        img.clone()
+    }
+}
+
+// Scale an image down to the configured size and add a caption bar below it. The text is scaled
+// up with the image, and cut short with "..." when it does not fit.
+fn annotate(img: &RgbImage, caption: &str) -> RgbImage {
+    let img = scale_down(img, CONFIG.images.annotated_size);
+
+    let scale = (img.width() / 240).max(1);
+    let padding = 3 * scale;
+    let advance = (font::GLYPH_WIDTH + 1) * scale;
+    let bar_height = font::GLYPH_HEIGHT * scale + 2 * padding;
+    let mut annotated =
+        RgbImage::from_pixel(img.width(), img.height() + bar_height, CAPTION_BACKGROUND);
+    imageops::replace(&mut annotated, &img, 0, 0);
+
+    let fits = (img.width().saturating_sub(2 * padding) / advance) as usize;
+    let mut text: Vec<char> = caption.chars().collect();
+    if text.len() > fits {
+        text.truncate(fits.saturating_sub(3));
+        text.extend("...".chars());
+        text.truncate(fits);
+    }
+    let top = img.height() + padding;
+    for (i, c) in text.into_iter().enumerate() {
+        draw_glyph(&mut annotated, c, padding + i as u32 * advance, top, scale);
+    }
+    annotated
+}
+
+// Draw a character with its top left corner at (x, y), each pixel of its glyph as a square of
+// 'scale' pixels.
+fn draw_glyph(img: &mut RgbImage, c: char, x: u32, y: u32, scale: u32) {
+    for (row, bits) in font::glyph(c).iter().enumerate() {
+        for column in 0..font::GLYPH_WIDTH {
+            if bits >> (font::GLYPH_WIDTH - 1 - column) & 1 == 0 {
+                continue;
+            }
+            let left = x + column * scale;
+            let top = y + row as u32 * scale;
+            for py in top..top + scale {
+                for px in left..left + scale {
+                    if px < img.width() && py < img.height() {
+                        img.put_pixel(px, py, CAPTION_TEXT);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,59 +1,478 @@
+use crate::config::{CombineRule, ModelOutput, CONFIG};
 use image;
+use image::{DynamicImage, RgbImage};
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::str::FromStr;
 use wasi_nn;
 
+mod augment;
+pub mod benchmark;
+mod decode;
+mod ensemble;
+pub mod explain;
+pub mod hierarchy;
 mod imagenet_classes;
+pub mod labels;
+pub mod open_set;
+mod preprocess;
+mod raw;
+mod registry;
 
-pub fn infer_image(image_name: &str) -> String {
-    let model_data = include_bytes!("models/mobilenet.pt");
+pub use augment::{Augmentation, TtaMode};
+pub use decode::{decode_image, for_each_frame, mime_type};
+pub use labels::LabelMap;
+pub use open_set::{OpenSetCheck, Verdict};
+pub use raw::{check_finite, check_input_shape, image_scores, run_batch, tensor_scores};
+pub use registry::{Model, Served, REGISTRY};
 
-    println!(
-        "Using torchscript binaries, size in bytes: {}",
-        model_data.len(),
-    );
+// The number of top predictions reported for an image
+const RESULT_COUNT: usize = 5;
 
-    let graph = wasi_nn::GraphBuilder::new(
-        wasi_nn::GraphEncoding::Pytorch,
-        wasi_nn::ExecutionTarget::CPU,
-    )
-    .build_from_bytes([model_data])
-    .unwrap();
-    println!("Loaded graph into wasi-nn with ID: {:?}", graph);
+// Per-request options controlling how an image is classified.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct InferenceOptions {
+    // Classify every frame of an animated GIF or multi-page TIFF instead of only the first one
+    pub all_frames: bool,
+    // When classifying all frames, only classify every n-th frame
+    pub frame_interval: Option<usize>,
+    // How the scores of the classified frames are combined into the overall predictions
+    pub aggregate: Aggregation,
+    // Test-time augmentation: average the scores of several variants of each frame
+    pub tta: TtaMode,
+    // Name of the model or ensemble to classify with, instead of the default one
+    pub model: Option<String>,
+    // Number of predictions reported for the image, instead of RESULT_COUNT
+    pub top_k: Option<usize>,
+    // Also report the categories of the label hierarchy this many levels above the classes
+    pub granularity: Option<usize>,
+    // Name of the label map applied to the results, instead of the one of the model
+    pub label_map: Option<String>,
+}
+
+// The rules for combining the scores of several frames.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    // Average the probabilities of every frame
+    #[default]
+    Mean,
+    // Rank classes by the fraction of frames that picked them as their top class
+    Vote,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_option(s)
+    }
+}
+
+// Parse an option value by the name that the web API accepts in its query string, so that the
+// command line tools accept the same names.
+pub(crate) fn parse_option<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    T::deserialize(StrDeserializer::<ValueError>::new(value)).map_err(|e| e.to_string())
+}
+
+// Classify the image stored in a file.
+pub fn infer_image(
+    image_name: &str,
+    options: &InferenceOptions,
+) -> Result<Classification, InferenceError> {
+    let mut file_img = File::open(image_name)
+        .map_err(|e| InferenceError::Internal(format!("failed to open {}: {}", image_name, e)))?;
+    let mut img_buf = Vec::new();
+    file_img
+        .read_to_end(&mut img_buf)
+        .map_err(|e| InferenceError::Internal(format!("failed to read {}: {}", image_name, e)))?;
+    infer_bytes(&img_buf, options)
+}
+
+// Classify an encoded image held in memory.
+pub fn infer_bytes(
+    img_buf: &[u8],
+    options: &InferenceOptions,
+) -> Result<Classification, InferenceError> {
+    let served = REGISTRY.resolve(options.model.as_deref())?;
+    let members = REGISTRY.members(&served);
+    match (options.granularity, &*hierarchy::HIERARCHY) {
+        (Some(_), None) => {
+            return Err(InferenceError::InvalidInput(
+                "granularity needs a label hierarchy, and none is configured".to_string(),
+            ))
+        }
+        (Some(levels), Some(hierarchy)) if levels > hierarchy.depth => {
+            return Err(InferenceError::InvalidInput(format!(
+                "granularity must be at most {}, the depth of the label hierarchy",
+                hierarchy.depth
+            )))
+        }
+        _ => {}
+    }
+    let max_decoded = CONFIG.limits.max_decoded_frames;
+    if options.frame_interval.unwrap_or(1) > max_decoded {
+        return Err(InferenceError::InvalidInput(format!(
+            "frame_interval must be at most {}",
+            max_decoded
+        )));
+    }
+    let label_map = labels::resolve(options.label_map.as_deref(), &served)?;
+
+    // Load every model that is run, a single one unless an ensemble was requested
+    let graphs = members
+        .iter()
+        .map(|(model, _)| model.load_graph())
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut contexts = Vec::new();
+    for graph in &graphs {
+        let context = graph.init_execution_context().map_err(internal_error)?;
+        eprintln!("Created wasi-nn execution context with ID: {:?}", context);
+        contexts.push(context);
+    }
+
+    let orientation = preprocess::exif_orientation(img_buf);
+    let background = CONFIG.preprocess.background;
+    let augmentations = options.tta.augmentations();
+
+    // Run a single decoded frame through every model and collect their output scores
+    let mut frame_outputs: Vec<(usize, Vec<Vec<f32>>)> = Vec::new();
+    let mut classify_frame = |index: usize, img: DynamicImage| -> Result<(), InferenceError> {
+        let img = preprocess::apply_orientation(img, orientation);
+        let img = preprocess::flatten_alpha(&img, background);
+        let mut outputs = Vec::new();
+        for ((model, _), context) in members.iter().zip(contexts.iter_mut()) {
+            eprintln!("Classifying frame {} with model {}", index, model.name);
+            outputs.push(run_model(model, context, &img, augmentations, background)?);
+        }
+        frame_outputs.push((index, outputs));
+        Ok(())
+    };
+
+    if options.all_frames {
+        let interval = options.frame_interval.unwrap_or(1).max(1);
+        decode::for_each_frame(img_buf, interval, CONFIG.limits.max_frames, classify_frame)?;
+    } else {
+        classify_frame(0, decode_image(img_buf)?)?;
+    }
+
+    let classification = match served {
+        Served::Model(model) => {
+            let frames: Vec<(usize, Vec<f32>)> = frame_outputs
+                .into_iter()
+                .map(|(index, mut outputs)| (index, outputs.remove(0)))
+                .collect();
+            summarize(&model.name, &frames, model.output, label_map, options)?
+        }
+        Served::Ensemble(ensemble) => {
+            // Turn the scores of every member into probabilities, frame by frame
+            let member_frames: Vec<Vec<(usize, Vec<f32>)>> = members
+                .iter()
+                .enumerate()
+                .map(|(m, (model, _))| {
+                    frame_outputs
+                        .iter()
+                        .map(|(index, outputs)| {
+                            (*index, ensemble::probabilities(&outputs[m], model.output))
+                        })
+                        .collect()
+                })
+                .collect();
+            let combined: Vec<(usize, Vec<f32>)> = (0..frame_outputs.len())
+                .map(|f| {
+                    let probabilities: Vec<(Vec<f32>, f32)> = member_frames
+                        .iter()
+                        .zip(&members)
+                        .map(|(frames, (_, weight))| (frames[f].1.clone(), *weight))
+                        .collect();
+                    (
+                        frame_outputs[f].0,
+                        ensemble::combine(ensemble.combine, &probabilities),
+                    )
+                })
+                .collect();
+
+            let mut classification = summarize(
+                &ensemble.name,
+                &combined,
+                ModelOutput::Probabilities,
+                label_map,
+                options,
+            )?;
+            classification.combine = Some(ensemble.combine);
+            // Rank votes say how the members order the classes, not how confident they are
+            if matches!(ensemble.combine, CombineRule::RankVote) {
+                classification.open_set = None;
+            }
+            classification.members = member_frames
+                .iter()
+                .zip(&members)
+                .map(|(frames, (model, weight))| {
+                    let scores = frame_scores(frames, ModelOutput::Probabilities, options)?;
+                    Ok(MemberClassification {
+                        model: model.name.clone(),
+                        weight: *weight,
+                        predictions: top_predictions(
+                            &scores,
+                            ModelOutput::Probabilities,
+                            label_map,
+                            options,
+                        ),
+                    })
+                })
+                .collect::<Result<_, InferenceError>>()?;
+            classification
+        }
+    };
+    eprint!("{}", classification);
+    Ok(classification)
+}
 
-    let mut context = graph.init_execution_context().unwrap();
-    println!("Created wasi-nn execution context with ID: {:?}", context);
+// Identify data by its SHA-256 hash, in hex. Images are cached and stored by it, so it must be
+// collision-resistant: an upload crafted to share the hash of another image would be served its
+// results and copies.
+pub fn content_hash(buf: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(buf))
+}
+
+// Compare a sent token with the expected one in time that does not depend on where they differ,
+// so that a token cannot be guessed byte by byte.
+pub fn same_token(token: &str, sent: &str) -> bool {
+    token.len() == sent.len()
+        && token
+            .bytes()
+            .zip(sent.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Decode the first frame of an encoded image into the upright RGB image that the models are fed,
+// e.g. to store a thumbnail of it.
+pub fn upright_image(buf: &[u8]) -> Result<RgbImage, InferenceError> {
+    let orientation = preprocess::exif_orientation(buf);
+    let img = preprocess::apply_orientation(decode_image(buf)?, orientation);
+    Ok(preprocess::flatten_alpha(
+        &img,
+        CONFIG.preprocess.background,
+    ))
+}
+
+// Run an upright RGB image through a model, once per augmentation, and return the averaged
+// output scores.
+fn run_model(
+    model: &Model,
+    context: &mut wasi_nn::GraphExecutionContext,
+    img: &RgbImage,
+    augmentations: &[Augmentation],
+    background: [u8; 3],
+) -> Result<Vec<f32>, InferenceError> {
+    let (width, height) = (model.input_width, model.input_height);
+    let mut scores = vec![0f32; model.classes];
+    for augmentation in augmentations {
+        // Load a tensor that precisely matches the graph input tensor
+        let tensor_data = image_to_tensor(&augmentation.apply(img, background), height, width);
+        let output_buffer = run_tensor(model, context, &tensor_data)?;
+        eprintln!("Executed graph inference ({})", augmentation);
+        for (score, p) in scores.iter_mut().zip(&output_buffer) {
+            *score += p / augmentations.len() as f32;
+        }
+    }
+    Ok(scores)
+}
 
-    // Load a tensor that precisely matches the graph input tensor
-    let tensor_data = image_to_tensor(image_name.to_string(), 224, 224);
-    println!("Read input tensor, size in bytes: {}", tensor_data.len());
+// Run a single input tensor, in the FP32 bytes layout of image_to_tensor, through a model and
+// return its output scores.
+fn run_tensor(
+    model: &Model,
+    context: &mut wasi_nn::GraphExecutionContext,
+    tensor_data: &[u8],
+) -> Result<Vec<f32>, InferenceError> {
+    eprintln!("Read input tensor, size in bytes: {}", tensor_data.len());
     context
-        .set_input(0, wasi_nn::TensorType::F32, &[1, 3, 224, 224], &tensor_data)
-        .unwrap();
+        .set_input(
+            0,
+            wasi_nn::TensorType::F32,
+            &[
+                1,
+                3,
+                model.input_height as usize,
+                model.input_width as usize,
+            ],
+            tensor_data,
+        )
+        .map_err(internal_error)?;
 
     // Execute the inference.
-    context.compute().unwrap();
-    println!("Executed graph inference");
+    context.compute().map_err(internal_error)?;
 
     // Retrieve the output.
-    let mut output_buffer = vec![0f32; 1000];
-    context.get_output(0, &mut output_buffer).unwrap();
-
-    const RESULT_COUNT: usize = 5;
-    let results = sort_results(&output_buffer);
-    let mut result_buffer = String::new();
-    for i in 0..RESULT_COUNT {
-        let buf = format!(
-            "   {}.) [{}]({:.4}){}\n",
-            i + 1,
-            results[i].0,
-            results[i].1,
-            imagenet_classes::IMAGENET_CLASSES[results[i].0]
+    let mut output_buffer = vec![0f32; model.classes];
+    context
+        .get_output(0, &mut output_buffer)
+        .map_err(internal_error)?;
+    Ok(output_buffer)
+}
+
+// Build the classification of an image from the scores of its classified frames, which are of the
+// given kind, applying the label map if any.
+fn summarize(
+    model: &str,
+    frames: &[(usize, Vec<f32>)],
+    output: ModelOutput,
+    label_map: Option<&LabelMap>,
+    options: &InferenceOptions,
+) -> Result<Classification, InferenceError> {
+    let scores = frame_scores(frames, output, options)?;
+    // Aggregated frames are probabilities, whatever the model outputs
+    let scores_output = if options.all_frames {
+        ModelOutput::Probabilities
+    } else {
+        output
+    };
+    let mut classification = Classification {
+        model: model.to_string(),
+        predictions: top_predictions(&scores, scores_output, label_map, options),
+        open_set: open_set::check(&scores, scores_output),
+        categories: Vec::new(),
+        frames: Vec::new(),
+        aggregation: None,
+        augmentations: Vec::new(),
+        combine: None,
+        members: Vec::new(),
+    };
+    if options.all_frames {
+        classification.frames = frames
+            .iter()
+            .map(|(index, scores)| FrameClassification {
+                frame: *index,
+                predictions: top_predictions(scores, output, label_map, options),
+            })
+            .collect();
+        classification.aggregation = Some(options.aggregate);
+    }
+    if !matches!(options.tta, TtaMode::Off) {
+        classification.augmentations = options.tta.augmentations().to_vec();
+    }
+    if let (Some(levels), Some(hierarchy)) = (options.granularity, &*hierarchy::HIERARCHY) {
+        classification.categories = hierarchy.categories(
+            &ensemble::probabilities(&scores, scores_output),
+            levels,
+            options.top_k.unwrap_or(RESULT_COUNT).max(1),
         );
-        print!("{}", buf);
-        result_buffer.push_str(&buf)
     }
-    return result_buffer;
+    Ok(classification)
+}
+
+// The scores of an image: the aggregate of its frames, as probabilities, when all frames were
+// classified, or else the scores of its only frame, of the given kind.
+fn frame_scores(
+    frames: &[(usize, Vec<f32>)],
+    output: ModelOutput,
+    options: &InferenceOptions,
+) -> Result<Vec<f32>, InferenceError> {
+    if options.all_frames {
+        aggregate_scores(frames, output, options.aggregate)
+    } else {
+        frames
+            .first()
+            .map(|(_, scores)| scores.clone())
+            .ok_or_else(no_frames)
+    }
+}
+
+// Combine the output scores of several frames, of the given kind, into a single probability per
+// class. The scores of each frame are turned into probabilities before they are averaged, so that
+// a frame with large logits does not outweigh the others.
+fn aggregate_scores(
+    frame_outputs: &[(usize, Vec<f32>)],
+    output: ModelOutput,
+    aggregation: Aggregation,
+) -> Result<Vec<f32>, InferenceError> {
+    let classes = frame_outputs.first().ok_or_else(no_frames)?.1.len();
+    let mut scores = vec![0f32; classes];
+    let frame_count = frame_outputs.len() as f32;
+    for (_, frame_output) in frame_outputs {
+        match aggregation {
+            Aggregation::Mean => {
+                let probabilities = ensemble::probabilities(frame_output, output);
+                for (score, p) in scores.iter_mut().zip(&probabilities) {
+                    *score += p / frame_count;
+                }
+            }
+            Aggregation::Vote => {
+                if let Some(top) = sort_results(frame_output).first() {
+                    scores[top.0] += 1. / frame_count;
+                }
+            }
+        }
+    }
+    Ok(scores)
+}
+
+// Take the best scoring classes of an output buffer, of the given kind, as many as requested. With
+// a label map, the sorted classes are remapped and filtered, and their probabilities renormalized.
+fn top_predictions(
+    buffer: &[f32],
+    output: ModelOutput,
+    label_map: Option<&LabelMap>,
+    options: &InferenceOptions,
+) -> Vec<Prediction> {
+    let count = options.top_k.unwrap_or(RESULT_COUNT).max(1);
+    match label_map {
+        Some(label_map) => label_map.apply(
+            sort_results(&ensemble::probabilities(buffer, output)),
+            count,
+        ),
+        None => rank(buffer, output, count),
+    }
+}
+
+// Take the 'count' best scoring classes of an output buffer, of the given kind, best first. Logits
+// are turned into probabilities first, so that every model reports the probabilities of its
+// classes.
+pub fn rank(buffer: &[f32], output: ModelOutput, count: usize) -> Vec<Prediction> {
+    sort_results(&ensemble::probabilities(buffer, output))
+        .into_iter()
+        .take(count)
+        .map(|InferenceResult(class_id, probability)| Prediction {
+            class_id,
+            label: label(class_id),
+            localized_label: None,
+            probability,
+        })
+        .collect()
+}
+
+// Look up the label of a class. Models with a larger label space than ImageNet get a generic
+// label for the extra classes.
+pub fn label(class_id: usize) -> String {
+    match imagenet_classes::IMAGENET_CLASSES.get(class_id) {
+        Some(label) => label.to_string(),
+        None => format!("class {}", class_id),
+    }
+}
+
+// Find the class named by a dataset label: its class ID, its full label, or any one of the
+// comma-separated names in the label. Case is ignored and underscores match spaces, so that
+// directory names such as "Granny_Smith" can be used.
+pub fn find_class(name: &str) -> Option<usize> {
+    if let Ok(class_id) = name.parse::<usize>() {
+        return (class_id < imagenet_classes::IMAGENET_CLASSES.len()).then_some(class_id);
+    }
+    let name = name.replace('_', " ").to_lowercase();
+    let name = name.trim();
+    imagenet_classes::IMAGENET_CLASSES.iter().position(|label| {
+        label.to_lowercase() == name
+            || label
+                .split(',')
+                .any(|synonym| synonym.trim().to_lowercase() == name)
+    })
 }
 
 // Sort the buffer of probabilities. The graph places the match probability for each class at the
@@ -65,19 +484,26 @@ fn sort_results(buffer: &[f32]) -> Vec<InferenceResult> {
         .enumerate()
         .map(|(c, p)| InferenceResult(c, *p))
         .collect();
-    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    // NaN scores, e.g. of a model fed out-of-range values, rank last instead of failing the sort
+    let rank = |p: f32| if p.is_nan() { f32::NEG_INFINITY } else { p };
+    results.sort_by(|a, b| rank(b.1).total_cmp(&rank(a.1)));
     results
 }
 
-// Take the image located at 'path', open it, resize it to height x width, and then converts
-// the pixel precision to FP32. The resulting BGR pixel vector is then returned.
-fn image_to_tensor(path: String, height: u32, width: u32) -> Vec<u8> {
-    let mut file_img = File::open(path).unwrap();
-    let mut img_buf = Vec::new();
-    file_img.read_to_end(&mut img_buf).unwrap();
-    let img = image::load_from_memory(&img_buf).unwrap().to_rgb8();
-    let resized =
-        image::imageops::resize(&img, height, width, ::image::imageops::FilterType::Triangle);
+// Take the upright RGB image, resize it to height x width, and then converts the pixel precision
+// to FP32. The resulting BGR pixel vector is then returned.
+fn image_to_tensor(img: &RgbImage, height: u32, width: u32) -> Vec<u8> {
+    normalize(&resize(img, height, width))
+}
+
+// Resize the image to the input size of the model.
+fn resize(img: &RgbImage, height: u32, width: u32) -> RgbImage {
+    image::imageops::resize(img, width, height, ::image::imageops::FilterType::Triangle)
+}
+
+// Normalize the pixels of a resized image with the ImageNet mean and standard deviation, and lay
+// them out as the planar FP32 bytes of the input tensor.
+fn normalize(resized: &RgbImage) -> Vec<u8> {
     let mut flat_img: Vec<f32> = Vec::new();
     for rgb in resized.pixels() {
         flat_img.push((rgb[0] as f32 / 255. - 0.485) / 0.229);
@@ -90,17 +516,262 @@ fn image_to_tensor(path: String, height: u32, width: u32) -> Vec<u8> {
     for c in 0..3 {
         for i in 0..(flat_img.len() / 3) {
             // Read the number as a f32 and break it into u8 bytes
-            let u8_f32: f32 = flat_img[i * 3 + c] as f32;
+            let u8_f32: f32 = flat_img[i * 3 + c];
             let u8_bytes = u8_f32.to_ne_bytes();
 
-            for j in 0..4 {
-                u8_f32_arr[((flat_img.len() / 3 * c + i) * 4) + j] = u8_bytes[j];
-            }
+            let start = (flat_img.len() / 3 * c + i) * 4;
+            u8_f32_arr[start..start + 4].copy_from_slice(&u8_bytes);
         }
     }
-    return u8_f32_arr;
+    u8_f32_arr
 }
 
 // A wrapper for class ID and match probabilities.
 #[derive(Debug, PartialEq)]
 struct InferenceResult(usize, f32);
+
+// A single labelled class in the results.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Prediction {
+    pub class_id: usize,
+    pub label: String,
+    // The label in the language of the request, for the web server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub localized_label: Option<String>,
+    pub probability: f32,
+}
+
+// The predictions for one frame of a multi-frame image.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FrameClassification {
+    pub frame: usize,
+    pub predictions: Vec<Prediction>,
+}
+
+// A category of the label hierarchy, with the summed probability of the classes below it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CategoryPrediction {
+    pub category: String,
+    // The category in the language of the request, for the web server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub localized_label: Option<String>,
+    pub probability: f32,
+}
+
+// The predictions of one member of an ensemble, for debugging the combined predictions.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MemberClassification {
+    pub model: String,
+    pub weight: f32,
+    pub predictions: Vec<Prediction>,
+}
+
+// The results of classifying an image. When every frame of a multi-frame image was classified,
+// the per-frame results are included and the top-level predictions are their aggregate. When
+// test-time augmentation was used, the augmentations whose scores were averaged are listed.
+// When an ensemble was used, the predictions of each member are included. When a granularity was
+// requested, the most probable categories of the label hierarchy are included. Unless it is
+// disabled, the verdict of the out-of-distribution check is included.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Classification {
+    pub model: String,
+    pub predictions: Vec<Prediction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_set: Option<OpenSetCheck>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<CategoryPrediction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<FrameClassification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<Aggregation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub augmentations: Vec<Augmentation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub combine: Option<CombineRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<MemberClassification>,
+}
+
+// Write a list of predictions in the preformatted text layout of the result page.
+fn write_predictions(f: &mut fmt::Formatter, predictions: &[Prediction]) -> fmt::Result {
+    for (i, prediction) in predictions.iter().enumerate() {
+        writeln!(
+            f,
+            "   {}.) [{}]({:.4}){}",
+            i + 1,
+            prediction.class_id,
+            prediction.probability,
+            prediction
+                .localized_label
+                .as_ref()
+                .unwrap_or(&prediction.label)
+        )?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Classification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.augmentations.is_empty() {
+            let names: Vec<String> = self.augmentations.iter().map(|a| a.to_string()).collect();
+            writeln!(f, "Averaged over augmentations: {}", names.join(", "))?;
+        }
+        for member in &self.members {
+            writeln!(f, "Model {} (weight {}):", member.model, member.weight)?;
+            write_predictions(f, &member.predictions)?;
+        }
+        if let Some(combine) = self.combine {
+            writeln!(f, "Ensemble {} ({:?}):", self.model, combine)?;
+        }
+        for frame in &self.frames {
+            writeln!(f, "Frame {}:", frame.frame)?;
+            write_predictions(f, &frame.predictions)?;
+        }
+        if let Some(aggregation) = self.aggregation {
+            writeln!(f, "Aggregated ({:?}):", aggregation)?;
+        }
+        write_predictions(f, &self.predictions)?;
+        if let Some(check) = &self.open_set {
+            writeln!(
+                f,
+                "Verdict: {} ({:?} {:.4})",
+                check.verdict, check.method, check.score
+            )?;
+        }
+        if !self.categories.is_empty() {
+            writeln!(f, "Categories:")?;
+        }
+        for (i, category) in self.categories.iter().enumerate() {
+            writeln!(
+                f,
+                "   {}.) ({:.4}){}",
+                i + 1,
+                category.probability,
+                category
+                    .localized_label
+                    .as_ref()
+                    .unwrap_or(&category.category)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The ways in which classifying an image can fail.
+#[derive(Debug)]
+pub enum InferenceError {
+    // The input is not an image that the deployment accepts
+    InvalidImage(String),
+    // The input exceeds the configured size limits
+    TooLarge(String),
+    // The input is not a tensor that the model accepts
+    InvalidInput(String),
+    // The requested model or ensemble is not served
+    UnknownModel(String),
+    // The server failed to read the image or to load or execute the model
+    Internal(String),
+}
+
+impl fmt::Display for InferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InferenceError::InvalidImage(msg) => write!(f, "invalid image: {}", msg),
+            InferenceError::TooLarge(msg) => write!(f, "image too large: {}", msg),
+            InferenceError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            InferenceError::UnknownModel(name) => write!(f, "unknown model: {}", name),
+            InferenceError::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+pub(crate) fn internal_error(e: wasi_nn::Error) -> InferenceError {
+    InferenceError::Internal(e.to_string())
+}
+
+// The error of an image none of whose frames was decoded, so that there are no scores to report.
+fn no_frames() -> InferenceError {
+    InferenceError::InvalidImage("no frame of the image was decoded".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_probabilities_for_logits_models() {
+        // Logits far outside [0, 1], of which the best ones are positive and the rest negative
+        let logits: Vec<f32> = (0..1000).map(|c| (c % 37) as f32 * 0.9 - 12.).collect();
+        let options = InferenceOptions {
+            top_k: Some(10),
+            ..InferenceOptions::default()
+        };
+        let predictions = top_predictions(&logits, ModelOutput::Logits, None, &options);
+        assert_eq!(predictions.len(), 10);
+        assert_eq!(predictions[0].class_id, 36);
+        for prediction in &predictions {
+            assert!((0. ..=1.).contains(&prediction.probability));
+        }
+        let total: f32 = predictions.iter().map(|p| p.probability).sum();
+        assert!(total <= 1.);
+        for pair in predictions.windows(2) {
+            assert!(pair[0].probability >= pair[1].probability);
+        }
+
+        // Probabilities are reported as they are
+        let mut probabilities = vec![0f32; 1000];
+        probabilities[7] = 0.75;
+        probabilities[3] = 0.25;
+        let predictions =
+            top_predictions(&probabilities, ModelOutput::Probabilities, None, &options);
+        let top: Vec<(usize, f32)> = predictions[..2]
+            .iter()
+            .map(|p| (p.class_id, p.probability))
+            .collect();
+        assert_eq!(top, [(7, 0.75), (3, 0.25)]);
+    }
+
+    #[test]
+    fn hashes_contents_with_sha256() {
+        assert_eq!(
+            content_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn averages_frames_as_probabilities() {
+        let frames = vec![(0, vec![2., 0., 0.]), (3, vec![0., 40., 0.])];
+        let scores = aggregate_scores(&frames, ModelOutput::Logits, Aggregation::Mean).unwrap();
+        // Each frame adds at most 1/2 to a class, however large its logits
+        let expected: Vec<f32> = (0..3)
+            .map(|c| {
+                frames
+                    .iter()
+                    .map(|(_, logits)| ensemble::probabilities(logits, ModelOutput::Logits)[c])
+                    .sum::<f32>()
+                    / 2.
+            })
+            .collect();
+        for (score, expected) in scores.iter().zip(&expected) {
+            assert!((score - expected).abs() < 1e-6);
+        }
+        assert!((scores.iter().sum::<f32>() - 1.).abs() < 1e-5);
+
+        let scores = aggregate_scores(&frames, ModelOutput::Logits, Aggregation::Vote).unwrap();
+        assert_eq!(scores, [0.5, 0.5, 0.]);
+    }
+
+    #[test]
+    fn rejects_images_without_frames() {
+        for all_frames in [false, true] {
+            let options = InferenceOptions {
+                all_frames,
+                ..InferenceOptions::default()
+            };
+            assert!(matches!(
+                frame_scores(&[], ModelOutput::Logits, &options),
+                Err(InferenceError::InvalidImage(_))
+            ));
+        }
+    }
+}
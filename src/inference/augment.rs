@@ -0,0 +1,237 @@
+use image::imageops;
+use image::{Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+// The fraction of the image kept by the crops and by zooming in
+const CROP_FRACTION: f32 = 0.9;
+// The size of the padded canvas, relative to the image, when zooming out
+const ZOOM_OUT_FACTOR: f32 = 1.1;
+
+// Test-time augmentation modes. Every mode runs the model once per augmentation, so the modes
+// trade latency for more robust predictions.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TtaMode {
+    // Only classify the image as it is
+    #[default]
+    Off,
+    // Also classify the horizontally flipped image
+    Flip,
+    // Also classify flipped, cropped and rescaled variants of the image
+    Full,
+}
+
+impl TtaMode {
+    // The augmentations whose scores are averaged for this mode.
+    pub fn augmentations(self) -> &'static [Augmentation] {
+        match self {
+            TtaMode::Off => &[Augmentation::Identity],
+            TtaMode::Flip => &[Augmentation::Identity, Augmentation::FlipHorizontal],
+            TtaMode::Full => &[
+                Augmentation::Identity,
+                Augmentation::FlipHorizontal,
+                Augmentation::CropTopLeft,
+                Augmentation::CropTopRight,
+                Augmentation::CropBottomLeft,
+                Augmentation::CropBottomRight,
+                Augmentation::ZoomIn,
+                Augmentation::ZoomOut,
+            ],
+        }
+    }
+}
+
+impl FromStr for TtaMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        super::parse_option(s)
+    }
+}
+
+// A variant of the input image that is classified during test-time augmentation.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Augmentation {
+    Identity,
+    FlipHorizontal,
+    CropTopLeft,
+    CropTopRight,
+    CropBottomLeft,
+    CropBottomRight,
+    // Crop the center of the image, enlarging its content
+    ZoomIn,
+    // Pad the image with the background color, shrinking its content
+    ZoomOut,
+}
+
+impl Augmentation {
+    // Build the augmented variant of an image. The variants are resized to the model input
+    // afterwards, like the original image.
+    pub fn apply(self, img: &RgbImage, background: [u8; 3]) -> Cow<'_, RgbImage> {
+        let (width, height) = img.dimensions();
+        let crop_width = ((width as f32 * CROP_FRACTION) as u32).max(1);
+        let crop_height = ((height as f32 * CROP_FRACTION) as u32).max(1);
+        let crop = |x: u32, y: u32| {
+            Cow::Owned(imageops::crop_imm(img, x, y, crop_width, crop_height).to_image())
+        };
+        match self {
+            Augmentation::Identity => Cow::Borrowed(img),
+            Augmentation::FlipHorizontal => Cow::Owned(imageops::flip_horizontal(img)),
+            Augmentation::CropTopLeft => crop(0, 0),
+            Augmentation::CropTopRight => crop(width - crop_width, 0),
+            Augmentation::CropBottomLeft => crop(0, height - crop_height),
+            Augmentation::CropBottomRight => crop(width - crop_width, height - crop_height),
+            Augmentation::ZoomIn => crop((width - crop_width) / 2, (height - crop_height) / 2),
+            Augmentation::ZoomOut => {
+                let canvas_width = (width as f32 * ZOOM_OUT_FACTOR) as u32;
+                let canvas_height = (height as f32 * ZOOM_OUT_FACTOR) as u32;
+                let mut canvas = RgbImage::from_pixel(canvas_width, canvas_height, Rgb(background));
+                imageops::overlay(
+                    &mut canvas,
+                    img,
+                    (canvas_width - width) / 2,
+                    (canvas_height - height) / 2,
+                );
+                Cow::Owned(canvas)
+            }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Augmentation::Identity => "identity",
+            Augmentation::FlipHorizontal => "flip_horizontal",
+            Augmentation::CropTopLeft => "crop_top_left",
+            Augmentation::CropTopRight => "crop_top_right",
+            Augmentation::CropBottomLeft => "crop_bottom_left",
+            Augmentation::CropBottomRight => "crop_bottom_right",
+            Augmentation::ZoomIn => "zoom_in",
+            Augmentation::ZoomOut => "zoom_out",
+        }
+    }
+}
+
+impl fmt::Display for Augmentation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    static BANANA: &[u8] = include_bytes!("../../test/banana.jpg");
+
+    // An image whose every pixel tells its coordinates apart, so that where a pixel ends up shows
+    // the transform
+    fn gradient(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| Rgb([x as u8, y as u8, (x + y) as u8]))
+    }
+
+    // The corner of the image that a crop is taken from, if it is one
+    fn crop_origin(augmentation: Augmentation, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (crop_width, crop_height) = crop_size(width, height);
+        let (right, bottom) = (width - crop_width, height - crop_height);
+        match augmentation {
+            Augmentation::CropTopLeft => Some((0, 0)),
+            Augmentation::CropTopRight => Some((right, 0)),
+            Augmentation::CropBottomLeft => Some((0, bottom)),
+            Augmentation::CropBottomRight => Some((right, bottom)),
+            Augmentation::ZoomIn => Some((right / 2, bottom / 2)),
+            _ => None,
+        }
+    }
+
+    fn crop_size(width: u32, height: u32) -> (u32, u32) {
+        (
+            ((width as f32 * CROP_FRACTION) as u32).max(1),
+            ((height as f32 * CROP_FRACTION) as u32).max(1),
+        )
+    }
+
+    #[test]
+    fn runs_the_variants_of_each_mode() {
+        let counts = [(TtaMode::Off, 1), (TtaMode::Flip, 2), (TtaMode::Full, 8)];
+        for (mode, count) in counts {
+            let augmentations = mode.augmentations();
+            assert_eq!(augmentations.len(), count, "{:?}", mode);
+            // The image itself is always classified, and no variant is classified twice
+            assert_eq!(augmentations[0].name(), "identity");
+            let mut names: Vec<&str> = augmentations.iter().map(|a| a.name()).collect();
+            names.sort();
+            names.dedup();
+            assert_eq!(names.len(), count, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn flips_into_a_mirror_image() {
+        let banana = image::load_from_memory(BANANA).unwrap().to_rgb8();
+        let (width, height) = banana.dimensions();
+        assert_ne!(width, height);
+        let flipped = Augmentation::FlipHorizontal.apply(&banana, [0, 0, 0]);
+        assert_eq!(flipped.dimensions(), (width, height));
+        for (x, y, pixel) in flipped.enumerate_pixels() {
+            assert_eq!(pixel, banana.get_pixel(width - 1 - x, y));
+        }
+        let twice = Augmentation::FlipHorizontal.apply(&flipped, [0, 0, 0]);
+        assert_eq!(*twice, banana);
+    }
+
+    #[test]
+    fn crops_within_the_image() {
+        let banana = image::load_from_memory(BANANA).unwrap().to_rgb8();
+        let images = [
+            banana,
+            gradient(1, 1),
+            gradient(1, 7),
+            gradient(7, 1),
+            gradient(2, 3),
+            gradient(10, 10),
+            gradient(250, 3),
+        ];
+        for img in &images {
+            let (width, height) = img.dimensions();
+            for &augmentation in TtaMode::Full.augmentations() {
+                let Some((x, y)) = crop_origin(augmentation, width, height) else {
+                    continue;
+                };
+                let variant = augmentation.apply(img, [0, 0, 0]);
+                let (crop_width, crop_height) = crop_size(width, height);
+                assert_eq!(
+                    variant.dimensions(),
+                    (crop_width, crop_height),
+                    "{} of {}x{}",
+                    augmentation,
+                    width,
+                    height
+                );
+                assert!(x + crop_width <= width && y + crop_height <= height);
+                assert_eq!(*variant, img.view(x, y, crop_width, crop_height).to_image());
+            }
+        }
+    }
+
+    #[test]
+    fn zooms_out_onto_the_background() {
+        let background = [0, 128, 255];
+        for img in [gradient(1, 1), gradient(3, 2), gradient(40, 20)] {
+            let (width, height) = img.dimensions();
+            let zoomed = Augmentation::ZoomOut.apply(&img, background);
+            let (canvas_width, canvas_height) = zoomed.dimensions();
+            assert!(canvas_width >= width && canvas_height >= height);
+            // The image is centered on the canvas, which shows the background around it
+            let (x, y) = ((canvas_width - width) / 2, (canvas_height - height) / 2);
+            assert_eq!(zoomed.view(x, y, width, height).to_image(), img);
+            if canvas_width > width {
+                assert_eq!(zoomed.get_pixel(0, 0).0, background);
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use super::{decode_image, internal_error, normalize, preprocess, resize};
+use super::{InferenceError, Model, Served, REGISTRY};
+use crate::config::CONFIG;
+use serde::Serialize;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// The stages of a single-image inference that are timed separately, in pipeline order
+const STAGES: [&str; 6] = [
+    "decode",
+    "resize",
+    "normalize",
+    "set_input",
+    "compute",
+    "get_output",
+];
+
+// What to run and how often.
+#[derive(Debug)]
+pub struct BenchmarkOptions {
+    // Name of the model to benchmark, instead of the default one
+    pub model: Option<String>,
+    // Untimed iterations run first, so that caches and lazy initialization don't skew the results
+    pub warmup: usize,
+    // Timed iterations, at least one
+    pub iterations: usize,
+    // Batch sizes to measure the throughput of the graph at
+    pub batch_sizes: Vec<usize>,
+}
+
+// The results of a benchmark run, saved as JSON to compare runs across model versions.
+#[derive(Debug, Serialize)]
+pub struct BenchmarkReport {
+    pub model: String,
+    pub image: String,
+    // Seconds since the Unix epoch when the run started
+    pub started_at: u64,
+    pub warmup: usize,
+    pub iterations: usize,
+    // Latency of every stage of a single-image inference, followed by their total
+    pub stages: Vec<StageLatency>,
+    pub batches: Vec<BatchThroughput>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StageLatency {
+    pub stage: String,
+    #[serde(flatten)]
+    pub latency: Latency,
+}
+
+// Latency statistics over the timed iterations, in milliseconds.
+#[derive(Debug, Serialize)]
+pub struct Latency {
+    pub mean_ms: f64,
+    pub min_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+// The throughput of the graph (set_input, compute and get_output) on batches of the same
+// preprocessed image. Models with a fixed batch size fail on other sizes; the error is recorded
+// instead of aborting the run.
+#[derive(Debug, Serialize)]
+pub struct BatchThroughput {
+    pub batch_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images_per_second: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Latency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Run the inference pipeline on an image over and over and time each of its stages.
+pub fn run_benchmark(
+    image_name: &str,
+    options: &BenchmarkOptions,
+) -> Result<BenchmarkReport, InferenceError> {
+    let model = match REGISTRY.resolve(options.model.as_deref())? {
+        Served::Model(model) => model,
+        Served::Ensemble(ensemble) => {
+            return Err(InferenceError::Internal(format!(
+                "{} is an ensemble, benchmark its models one by one",
+                ensemble.name
+            )))
+        }
+    };
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let iterations = options.iterations.max(1);
+
+    let graph = model.load_graph()?;
+    let mut context = graph.init_execution_context().map_err(internal_error)?;
+    // Reading the file is left out of the timings, only decoding it is measured
+    let img_buf = fs::read(image_name)
+        .map_err(|e| InferenceError::Internal(format!("failed to read {}: {}", image_name, e)))?;
+    let background = CONFIG.preprocess.background;
+    let dimensions = [
+        1,
+        3,
+        model.input_height as usize,
+        model.input_width as usize,
+    ];
+
+    let mut timings: Vec<Vec<Duration>> = vec![Vec::new(); STAGES.len()];
+    let mut tensor_data = Vec::new();
+    for iteration in 0..options.warmup + iterations {
+        let mut durations = Vec::new();
+        let mut start = Instant::now();
+        let mut lap = |durations: &mut Vec<Duration>| {
+            let now = Instant::now();
+            durations.push(now - start);
+            start = now;
+        };
+
+        let img = decode_image(&img_buf)?;
+        let img = preprocess::apply_orientation(img, preprocess::exif_orientation(&img_buf));
+        let img = preprocess::flatten_alpha(&img, background);
+        lap(&mut durations);
+        let resized = resize(&img, model.input_height, model.input_width);
+        lap(&mut durations);
+        tensor_data = normalize(&resized);
+        lap(&mut durations);
+        context
+            .set_input(0, wasi_nn::TensorType::F32, &dimensions, &tensor_data)
+            .map_err(internal_error)?;
+        lap(&mut durations);
+        context.compute().map_err(internal_error)?;
+        lap(&mut durations);
+        let mut output_buffer = vec![0f32; model.classes];
+        context
+            .get_output(0, &mut output_buffer)
+            .map_err(internal_error)?;
+        lap(&mut durations);
+
+        if iteration >= options.warmup {
+            for (stage, duration) in timings.iter_mut().zip(durations) {
+                stage.push(duration);
+            }
+        }
+    }
+
+    let totals: Vec<Duration> = (0..iterations)
+        .map(|i| timings.iter().map(|stage| stage[i]).sum())
+        .collect();
+    let mut stages: Vec<StageLatency> = STAGES
+        .iter()
+        .zip(&timings)
+        .map(|(stage, durations)| StageLatency {
+            stage: stage.to_string(),
+            latency: latency(durations),
+        })
+        .collect();
+    stages.push(StageLatency {
+        stage: "total".to_string(),
+        latency: latency(&totals),
+    });
+
+    let batches = options
+        .batch_sizes
+        .iter()
+        .map(|batch_size| {
+            match benchmark_batch(model, &mut context, &tensor_data, *batch_size, options) {
+                Ok(durations) => {
+                    let seconds: f64 = durations.iter().map(|d| d.as_secs_f64()).sum();
+                    BatchThroughput {
+                        batch_size: *batch_size,
+                        images_per_second: Some((batch_size * durations.len()) as f64 / seconds),
+                        latency: Some(latency(&durations)),
+                        error: None,
+                    }
+                }
+                Err(e) => BatchThroughput {
+                    batch_size: *batch_size,
+                    images_per_second: None,
+                    latency: None,
+                    error: Some(e.to_string()),
+                },
+            }
+        })
+        .collect();
+
+    Ok(BenchmarkReport {
+        model: model.name.clone(),
+        image: image_name.to_string(),
+        started_at,
+        warmup: options.warmup,
+        iterations,
+        stages,
+        batches,
+    })
+}
+
+// Time the graph on a batch made of 'batch_size' copies of the same input tensor.
+fn benchmark_batch(
+    model: &Model,
+    context: &mut wasi_nn::GraphExecutionContext,
+    tensor_data: &[u8],
+    batch_size: usize,
+    options: &BenchmarkOptions,
+) -> Result<Vec<Duration>, InferenceError> {
+    if batch_size == 0 {
+        return Err(InferenceError::Internal(
+            "batch size must be at least 1".to_string(),
+        ));
+    }
+    let batch = tensor_data.repeat(batch_size);
+    let dimensions = [
+        batch_size,
+        3,
+        model.input_height as usize,
+        model.input_width as usize,
+    ];
+    let mut output_buffer = vec![0f32; model.classes * batch_size];
+    let mut durations = Vec::new();
+    for iteration in 0..options.warmup + options.iterations.max(1) {
+        let start = Instant::now();
+        context
+            .set_input(0, wasi_nn::TensorType::F32, &dimensions, &batch)
+            .map_err(internal_error)?;
+        context.compute().map_err(internal_error)?;
+        context
+            .get_output(0, &mut output_buffer)
+            .map_err(internal_error)?;
+        if iteration >= options.warmup {
+            durations.push(start.elapsed());
+        }
+    }
+    Ok(durations)
+}
+
+// Summarize durations, using the nearest-rank method for the percentiles.
+fn latency(durations: &[Duration]) -> Latency {
+    let mut ms: Vec<f64> = durations.iter().map(|d| d.as_secs_f64() * 1000.).collect();
+    ms.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let percentile = |p: f64| {
+        let rank = (p / 100. * ms.len() as f64).ceil() as usize;
+        ms[rank.clamp(1, ms.len()) - 1]
+    };
+    Latency {
+        mean_ms: ms.iter().sum::<f64>() / ms.len() as f64,
+        min_ms: ms[0],
+        p50_ms: percentile(50.),
+        p95_ms: percentile(95.),
+        p99_ms: percentile(99.),
+        max_ms: ms[ms.len() - 1],
+    }
+}
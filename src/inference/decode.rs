@@ -0,0 +1,43 @@
+// The image decoder bounded by the configured limits. The decoder itself lives in the
+// wasm-ai-decode crate, which has none of the WASI dependencies of the server, so that it can be
+// tested and fuzzed natively.
+use super::InferenceError;
+use crate::config::CONFIG;
+use image::DynamicImage;
+use lazy_static::lazy_static;
+use wasm_ai_decode::{DecodeError, Limits};
+
+pub use wasm_ai_decode::mime_type;
+
+// Define a lazy-static variable to store the limits of the decoder, from the configuration
+lazy_static! {
+    static ref LIMITS: Limits = CONFIG.limits.decode_limits();
+}
+
+impl From<DecodeError> for InferenceError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::InvalidImage(msg) => InferenceError::InvalidImage(msg),
+            DecodeError::TooLarge(msg) => InferenceError::TooLarge(msg),
+        }
+    }
+}
+
+// Decode untrusted image bytes into an image, within the configured limits.
+pub fn decode_image(buf: &[u8]) -> Result<DynamicImage, InferenceError> {
+    Ok(wasm_ai_decode::decode_image(buf, &LIMITS)?)
+}
+
+// Decode the frames of an animated or multi-page image one at a time, within the configured
+// limits, passing every interval-th frame and its index to 'f'.
+pub fn for_each_frame<F>(
+    buf: &[u8],
+    interval: usize,
+    max_frames: usize,
+    f: F,
+) -> Result<(), InferenceError>
+where
+    F: FnMut(usize, DynamicImage) -> Result<(), InferenceError>,
+{
+    wasm_ai_decode::for_each_frame(buf, interval, max_frames, &LIMITS, f)
+}
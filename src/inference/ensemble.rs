@@ -0,0 +1,103 @@
+use crate::config::{CombineRule, ModelOutput};
+
+// Turn the output scores of a model into class probabilities.
+pub fn probabilities(scores: &[f32], output: ModelOutput) -> Vec<f32> {
+    match output {
+        ModelOutput::Probabilities => scores.to_vec(),
+        ModelOutput::Logits => {
+            // Subtract the largest score first so that the exponentials cannot overflow
+            let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            let exps: Vec<f32> = scores.iter().map(|s| (s - max).exp()).collect();
+            let sum: f32 = exps.iter().sum();
+            exps.iter().map(|e| e / sum).collect()
+        }
+    }
+}
+
+// Combine the class probabilities of the members of an ensemble, given with their weights,
+// into a single score per class. The scores of every rule are turned into a distribution over the
+// classes, since they are used as the probabilities of the ensemble.
+pub fn combine(rule: CombineRule, members: &[(Vec<f32>, f32)]) -> Vec<f32> {
+    let classes = members[0].0.len();
+    let total_weight: f32 = members.iter().map(|(_, weight)| weight).sum();
+    let mut scores = vec![0f32; classes];
+    match rule {
+        CombineRule::Mean => {
+            for (probabilities, weight) in members {
+                for (score, p) in scores.iter_mut().zip(probabilities) {
+                    *score += p * weight / total_weight;
+                }
+            }
+        }
+        CombineRule::Max => {
+            for (probabilities, _) in members {
+                for (score, p) in scores.iter_mut().zip(probabilities) {
+                    *score = score.max(*p);
+                }
+            }
+            let sum: f32 = scores.iter().sum();
+            if sum > 0. {
+                scores.iter_mut().for_each(|score| *score /= sum);
+            }
+        }
+        CombineRule::RankVote => {
+            // Every member gives each class a point for every class ranked below it. Each place
+            // gained multiplies the probability by e, so that a class ranked first by every
+            // member gets about 0.63 however many classes there are, and less when they disagree.
+            for (probabilities, weight) in members {
+                let mut ranking: Vec<usize> = (0..classes).collect();
+                ranking.sort_by(|a, b| probabilities[*b].total_cmp(&probabilities[*a]));
+                for (rank, class_id) in ranking.into_iter().enumerate() {
+                    let points = (classes - 1 - rank) as f32;
+                    scores[class_id] += points * weight / total_weight;
+                }
+            }
+            scores = probabilities(&scores, ModelOutput::Logits);
+        }
+    }
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(scores: &[f32], expected: &[f32]) {
+        assert_eq!(scores.len(), expected.len());
+        for (score, expected) in scores.iter().zip(expected) {
+            assert!((score - expected).abs() < 1e-5);
+        }
+    }
+
+    // Two members that disagree on the best of three classes, the second one weighing 3 times more
+    fn members() -> Vec<(Vec<f32>, f32)> {
+        vec![(vec![0.5, 0.3, 0.2], 1.), (vec![0.1, 0.6, 0.3], 3.)]
+    }
+
+    #[test]
+    fn averages_with_the_weights() {
+        let scores = combine(CombineRule::Mean, &members());
+        assert_close(&scores, &[0.2, 0.525, 0.275]);
+    }
+
+    #[test]
+    fn normalizes_the_highest_probabilities() {
+        let scores = combine(CombineRule::Max, &members());
+        assert_close(&scores, &[0.5 / 1.4, 0.6 / 1.4, 0.3 / 1.4]);
+    }
+
+    #[test]
+    fn turns_rank_votes_into_probabilities() {
+        // The first member ranks the classes 0, 1, 2 and the second one 1, 2, 0
+        let scores = combine(CombineRule::RankVote, &members());
+        let votes = [2. * 0.25, 1. * 0.25 + 2. * 0.75, 1. * 0.75];
+        assert_close(&scores, &probabilities(&votes, ModelOutput::Logits));
+        assert!(scores[1] > scores[2] && scores[2] > scores[0]);
+
+        // A class that every member ranks first stands out among many classes
+        let ranked: Vec<f32> = (0..1000).map(|c| 1. - c as f32 / 1000.).collect();
+        let scores = combine(CombineRule::RankVote, &[(ranked.clone(), 1.), (ranked, 2.)]);
+        assert!((scores[0] - (1. - (-1f32).exp())).abs() < 1e-3);
+        assert!((scores.iter().sum::<f32>() - 1.).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,201 @@
+// Occlusion sensitivity: which parts of an image the score of its top class depends on. A gray
+// patch is slid over the input of the model, and the drop of the score at each position of the
+// patch is attributed to the pixels it covers.
+use super::{internal_error, normalize, rank, resize, run_tensor, upright_image};
+use super::{InferenceError, Model, Served, REGISTRY};
+use crate::config::CONFIG;
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+
+// Color of the occluding patch, the middle of the input range
+const OCCLUSION_COLOR: Rgb<u8> = Rgb([128, 128, 128]);
+
+// Per-request options of an explanation, falling back to the configured ones.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ExplainOptions {
+    // Explain the top class of the image along with its classification
+    pub explain: bool,
+    // Side of the occluding patch, in pixels of the model input
+    pub patch_size: Option<u32>,
+    // Distance between two positions of the patch, in pixels of the model input
+    pub stride: Option<u32>,
+    // Most occluded images run through the model, at most the configured budget
+    pub max_evaluations: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub model: String,
+    // The explained class, the top class of the unoccluded image
+    pub class_id: usize,
+    pub label: String,
+    pub score: f32,
+    pub patch_size: u32,
+    // The stride actually used, which is widened to fit the compute budget
+    pub stride: u32,
+    pub evaluations: usize,
+    // Size of the model input, which the heatmap covers
+    #[serde(skip)]
+    pub width: u32,
+    #[serde(skip)]
+    pub height: u32,
+    // Score drop attributed to each pixel of the model input, row by row, scaled to [0, 1]
+    #[serde(skip)]
+    pub heatmap: Vec<f32>,
+}
+
+// The model that runs an explanation, and the positions of its patch.
+struct Plan {
+    model: &'static Model,
+    patch_size: u32,
+    stride: u32,
+    xs: Vec<u32>,
+    ys: Vec<u32>,
+}
+
+// Check that an explanation can run, so that a request which cannot be explained is rejected
+// before its image is classified.
+pub fn validate(model: Option<&str>, options: &ExplainOptions) -> Result<(), InferenceError> {
+    plan(model, options).map(|_| ())
+}
+
+// Pick the model and the positions of the patch of an explanation. Ensembles are not supported,
+// since their members may take inputs of different sizes. The stride is widened until the
+// positions fit in the compute budget, and a budget that is too small to cover the whole input
+// is rejected rather than exceeded.
+fn plan(model: Option<&str>, options: &ExplainOptions) -> Result<Plan, InferenceError> {
+    let model = match REGISTRY.resolve(model)? {
+        Served::Model(model) => model,
+        Served::Ensemble(ensemble) => {
+            return Err(InferenceError::InvalidInput(format!(
+                "{} is an ensemble, only models can be explained",
+                ensemble.name
+            )))
+        }
+    };
+    let config = &CONFIG.explain;
+    let (width, height) = (model.input_width, model.input_height);
+    let patch_size = options
+        .patch_size
+        .unwrap_or(config.patch_size)
+        .clamp(1, width.min(height));
+    let max_evaluations = options
+        .max_evaluations
+        .unwrap_or(config.max_evaluations)
+        .clamp(1, config.max_evaluations.max(1));
+    // Widen the stride until the positions of the patch fit in the compute budget
+    let mut stride = options.stride.unwrap_or(config.stride).max(1);
+    let mut xs = offsets(width, patch_size, stride);
+    let mut ys = offsets(height, patch_size, stride);
+    while xs.len() * ys.len() > max_evaluations && stride < width.max(height) {
+        stride += 1;
+        xs = offsets(width, patch_size, stride);
+        ys = offsets(height, patch_size, stride);
+    }
+    if xs.len() * ys.len() > max_evaluations {
+        return Err(InferenceError::InvalidInput(format!(
+            "max_evaluations must be at least {} to cover the input with a {}-pixel patch",
+            xs.len() * ys.len(),
+            patch_size
+        )));
+    }
+    Ok(Plan {
+        model,
+        patch_size,
+        stride,
+        xs,
+        ys,
+    })
+}
+
+// Explain the top class of an image with a model.
+pub fn explain(
+    img_buf: &[u8],
+    model: Option<&str>,
+    options: &ExplainOptions,
+) -> Result<Explanation, InferenceError> {
+    let Plan {
+        model,
+        patch_size,
+        stride,
+        xs,
+        ys,
+    } = plan(model, options)?;
+    let (width, height) = (model.input_width, model.input_height);
+    let graph = model.load_graph()?;
+    let mut context = graph.init_execution_context().map_err(internal_error)?;
+    let resized = resize(&upright_image(img_buf)?, height, width);
+    let baseline = run_tensor(model, &mut context, &normalize(&resized))?;
+    let top = rank(&baseline, model.output, 1).remove(0);
+
+    let mut drops = vec![0f32; (width * height) as usize];
+    let mut counts = vec![0u32; (width * height) as usize];
+    for &y in &ys {
+        for &x in &xs {
+            let mut occluded = resized.clone();
+            for py in y..y + patch_size {
+                for px in x..x + patch_size {
+                    occluded.put_pixel(px, py, OCCLUSION_COLOR);
+                }
+            }
+            let scores = run_tensor(model, &mut context, &normalize(&occluded))?;
+            let drop = baseline[top.class_id] - scores[top.class_id];
+            for py in y..y + patch_size {
+                for px in x..x + patch_size {
+                    let i = (py * width + px) as usize;
+                    drops[i] += drop;
+                    counts[i] += 1;
+                }
+            }
+        }
+    }
+    eprintln!(
+        "Explained class {} with {} occluded images",
+        top.class_id,
+        xs.len() * ys.len()
+    );
+
+    // Average the overlapping positions, and scale by the largest drop. Occlusions that raise
+    // the score are not evidence for the class, so they count as no drop.
+    let drops: Vec<f32> = drops
+        .iter()
+        .zip(&counts)
+        .map(|(drop, count)| drop / (*count).max(1) as f32)
+        .collect();
+    let largest = drops.iter().cloned().fold(0f32, f32::max);
+    let heatmap = drops
+        .iter()
+        .map(|drop| {
+            if largest > 0. {
+                (drop / largest).max(0.)
+            } else {
+                0.
+            }
+        })
+        .collect();
+
+    Ok(Explanation {
+        model: model.name.clone(),
+        class_id: top.class_id,
+        label: top.label,
+        score: top.probability,
+        patch_size,
+        stride,
+        evaluations: xs.len() * ys.len(),
+        width,
+        height,
+        heatmap,
+    })
+}
+
+// The positions of a patch along a side of the input, one every 'stride' pixels, plus one flush
+// with the end so that the whole side is covered.
+fn offsets(length: u32, patch_size: u32, stride: u32) -> Vec<u32> {
+    let last = length - patch_size;
+    let mut offsets: Vec<u32> = (0..=last).step_by(stride as usize).collect();
+    if offsets.last() != Some(&last) {
+        offsets.push(last);
+    }
+    offsets
+}
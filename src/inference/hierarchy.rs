@@ -0,0 +1,237 @@
+// A label hierarchy grouping the ImageNet classes into broader categories, such as the WordNet
+// hypernyms of the classes. It is read from a file of "child<TAB>parent" lines, where a child is
+// an ImageNet class (its ID or one of its names) or a category that is itself the parent of other
+// lines, and a parent is always a category. Classes and categories may have several parents.
+use super::{content_hash, find_class, label, CategoryPrediction};
+use crate::config::CONFIG;
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+// Define a lazy-static variable to store the configured hierarchy, if any
+lazy_static! {
+    pub static ref HIERARCHY: Option<Hierarchy> = CONFIG.hierarchy.as_deref().map(|path| {
+        Hierarchy::load(path).unwrap_or_else(|e| panic!("Invalid label hierarchy {}: {}", path, e))
+    });
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Node {
+    Class(usize),
+    Category(String),
+}
+
+impl Node {
+    fn name(&self) -> String {
+        match self {
+            Node::Class(class_id) => label(*class_id),
+            Node::Category(name) => name.clone(),
+        }
+    }
+}
+
+// The marks of the nodes walked through when checking that the hierarchy is acyclic. Unmarked
+// nodes are yet to be walked through.
+#[derive(Debug, Clone, Copy)]
+enum Mark {
+    // Its ancestors are being walked through, so reaching it again closes a cycle
+    Walking,
+    // Its ancestors were all walked through without closing a cycle
+    Checked,
+}
+
+#[derive(Debug)]
+pub struct Hierarchy {
+    parents: HashMap<Node, Vec<String>>,
+    // Number of levels of the longest chain of parents, above which every class stands for its
+    // root categories
+    pub depth: usize,
+    // Hash of the file, so that cached results are not reused once it changes
+    pub fingerprint: String,
+}
+
+impl Hierarchy {
+    fn load(path: &str) -> Result<Hierarchy, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let hierarchy = Hierarchy::parse(&contents)?;
+        eprintln!(
+            "Loaded label hierarchy with {} entries from: {}",
+            hierarchy.parents.len(),
+            path
+        );
+        Ok(hierarchy)
+    }
+
+    // Parse the lines of a hierarchy file, and check that the hierarchy is acyclic.
+    fn parse(contents: &str) -> Result<Hierarchy, String> {
+        let mut parents: HashMap<Node, Vec<String>> = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (child, parent) = line
+                .split_once('\t')
+                .ok_or_else(|| format!("line {}: expected child<TAB>parent", number + 1))?;
+            let child = match find_class(child) {
+                Some(class_id) => Node::Class(class_id),
+                None => Node::Category(child.trim().to_string()),
+            };
+            parents
+                .entry(child)
+                .or_default()
+                .push(parent.trim().to_string());
+        }
+        let mut hierarchy = Hierarchy {
+            parents,
+            depth: 0,
+            fingerprint: content_hash(contents.as_bytes()),
+        };
+        let mut marks = HashMap::new();
+        for node in hierarchy.parents.keys() {
+            hierarchy.check_acyclic(node, &mut marks)?;
+        }
+        let mut heights = HashMap::new();
+        hierarchy.depth = hierarchy
+            .parents
+            .keys()
+            .map(|node| hierarchy.height(node, &mut heights))
+            .max()
+            .unwrap_or(0);
+        Ok(hierarchy)
+    }
+
+    fn parents(&self, node: &Node) -> &[String] {
+        self.parents.get(node).map_or(&[], |parents| parents)
+    }
+
+    // Reject a hierarchy in which a category is its own ancestor, which would make the levels
+    // above it endless. A depth-first walk marks the nodes whose ancestors it is walking through
+    // (grey) and those whose ancestors were all checked (black), so that each node is walked
+    // through once however many children share it.
+    fn check_acyclic(&self, node: &Node, marks: &mut HashMap<Node, Mark>) -> Result<(), String> {
+        match marks.get(node) {
+            Some(Mark::Checked) => return Ok(()),
+            Some(Mark::Walking) => return Err(format!("{} is its own ancestor", node.name())),
+            None => {}
+        }
+        marks.insert(node.clone(), Mark::Walking);
+        for parent in self.parents(node) {
+            self.check_acyclic(&Node::Category(parent.clone()), marks)?;
+        }
+        marks.insert(node.clone(), Mark::Checked);
+        Ok(())
+    }
+
+    // Number of levels of the longest chain of parents above a node, memoized since categories
+    // are shared by many classes. The hierarchy must be acyclic.
+    fn height(&self, node: &Node, heights: &mut HashMap<Node, usize>) -> usize {
+        if let Some(height) = heights.get(node) {
+            return *height;
+        }
+        let height = self
+            .parents(node)
+            .iter()
+            .map(|parent| 1 + self.height(&Node::Category(parent.clone()), heights))
+            .max()
+            .unwrap_or(0);
+        heights.insert(node.clone(), height);
+        height
+    }
+
+    // The ancestors of a class a number of levels up. A class or category without parents stands
+    // for itself at the levels above it, so the walk stops once the ancestors stop changing.
+    fn ancestors(&self, class_id: usize, levels: usize) -> Vec<Node> {
+        let mut nodes = vec![Node::Class(class_id)];
+        for _ in 0..levels {
+            let mut next = Vec::new();
+            for node in &nodes {
+                match self.parents(node) {
+                    [] => next.push(node.clone()),
+                    parents => next.extend(parents.iter().cloned().map(Node::Category)),
+                }
+            }
+            next.sort();
+            next.dedup();
+            if next == nodes {
+                break;
+            }
+            nodes = next;
+        }
+        nodes
+    }
+
+    // Sum the probabilities of the classes into their ancestors a number of levels up, and take
+    // the 'count' most probable categories. A class with several ancestors counts towards each.
+    pub fn categories(
+        &self,
+        probabilities: &[f32],
+        levels: usize,
+        count: usize,
+    ) -> Vec<CategoryPrediction> {
+        let mut sums: BTreeMap<Node, f32> = BTreeMap::new();
+        for (class_id, probability) in probabilities.iter().enumerate() {
+            for ancestor in self.ancestors(class_id, levels) {
+                *sums.entry(ancestor).or_default() += probability;
+            }
+        }
+        let mut categories: Vec<CategoryPrediction> = sums
+            .into_iter()
+            .map(|(node, probability)| CategoryPrediction {
+                category: node.name(),
+                localized_label: None,
+                probability,
+            })
+            .collect();
+        categories.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+        categories.truncate(count);
+        categories
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_shared_ancestors_once() {
+        // Every category has two parents on the level above, so a class has 2^n paths to the top
+        // level n, and walking each of them would never end
+        let mut lines = vec![
+            "banana\tlevel 0 a".to_string(),
+            "banana\tlevel 0 b".to_string(),
+        ];
+        for level in 0..48 {
+            for child in ["a", "b"] {
+                for parent in ["a", "b"] {
+                    lines.push(format!(
+                        "level {} {}\tlevel {} {}",
+                        level,
+                        child,
+                        level + 1,
+                        parent
+                    ));
+                }
+            }
+        }
+        let hierarchy = Hierarchy::parse(&lines.join("\n")).unwrap();
+        assert_eq!(hierarchy.depth, 49);
+        let banana = find_class("banana").unwrap();
+        let top: Vec<String> = hierarchy
+            .ancestors(banana, 49)
+            .iter()
+            .map(Node::name)
+            .collect();
+        assert_eq!(top, ["level 48 a", "level 48 b"]);
+    }
+
+    #[test]
+    fn rejects_categories_that_are_their_own_ancestors() {
+        let cycle = "banana\tfruit\nfruit\tproduce\nproduce\tfood\nfood\tfruit";
+        let err = Hierarchy::parse(cycle).unwrap_err();
+        assert!(err.ends_with("is its own ancestor"), "{}", err);
+        // Categories shared by several children are not cycles
+        let diamond = "banana\tfruit\nbanana\tyellow\nfruit\tfood\nyellow\tfood";
+        assert_eq!(Hierarchy::parse(diamond).unwrap().depth, 2);
+    }
+}
@@ -0,0 +1,215 @@
+// Label maps, for deployments that only care about some of the classes: each maps classes to
+// labels of its own (several classes may share a label), hides denied classes and keeps only an
+// allowlist. They are applied to the sorted results, and the probabilities of the classes that are
+// kept are renormalized so that they sum to 1.
+use super::{content_hash, find_class, label, InferenceError, InferenceResult, Prediction, Served};
+use crate::config::CONFIG;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+// Number of ImageNet classes that labels are looked up among
+const IMAGENET_CLASS_COUNT: usize = 1000;
+
+// Name a request gives to do without the label map of its model
+pub const NO_LABEL_MAP: &str = "none";
+
+// Define a lazy-static variable to store the configured label maps, by name
+lazy_static! {
+    pub static ref LABEL_MAPS: HashMap<String, LabelMap> = CONFIG
+        .label_maps
+        .iter()
+        .map(|(name, path)| {
+            let label_map = LabelMap::load(name, path)
+                .unwrap_or_else(|e| panic!("Invalid label map {} in {}: {}", name, path, e));
+            (name.clone(), label_map)
+        })
+        .collect();
+}
+
+// A label map file. Classes are named by ID or by one of their names, and the allow and deny
+// lists may also name a label of the map, standing for every class mapped to it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LabelMapFile {
+    // The label reported for a class, instead of its ImageNet label
+    labels: BTreeMap<String, String>,
+    // The only classes reported, every class when empty
+    allow: Vec<String>,
+    // Classes never reported
+    deny: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct LabelMap {
+    labels: HashMap<usize, String>,
+    allowed: Option<HashSet<usize>>,
+    denied: HashSet<usize>,
+    // Hash of the file, so that cached results are not reused once it changes
+    pub fingerprint: String,
+}
+
+impl LabelMap {
+    fn load(name: &str, path: &str) -> Result<LabelMap, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let file: LabelMapFile = toml::from_str(&contents).map_err(|e| e.to_string())?;
+        let mut labels = HashMap::new();
+        for (class, label) in &file.labels {
+            let class_id = find_class(class).ok_or_else(|| format!("unknown class {}", class))?;
+            labels.insert(class_id, label.clone());
+        }
+        let classes = |names: &[String]| -> Result<HashSet<usize>, String> {
+            let mut classes = HashSet::new();
+            for name in names {
+                let mapped: Vec<usize> = labels
+                    .iter()
+                    .filter(|(_, label)| *label == name)
+                    .map(|(class_id, _)| *class_id)
+                    .collect();
+                match (mapped.is_empty(), find_class(name)) {
+                    (false, _) => classes.extend(mapped),
+                    (true, Some(class_id)) => {
+                        classes.insert(class_id);
+                    }
+                    (true, None) => return Err(format!("unknown class or label {}", name)),
+                }
+            }
+            Ok(classes)
+        };
+        let label_map = LabelMap {
+            allowed: (!file.allow.is_empty())
+                .then(|| classes(&file.allow))
+                .transpose()?,
+            denied: classes(&file.deny)?,
+            labels,
+            fingerprint: content_hash(contents.as_bytes()),
+        };
+        // Every image would be classified as nothing at all
+        if !(0..IMAGENET_CLASS_COUNT).any(|class_id| label_map.keeps(class_id)) {
+            return Err("every class is denied".to_string());
+        }
+        eprintln!("Loaded label map {} from: {}", name, path);
+        Ok(label_map)
+    }
+
+    fn keeps(&self, class_id: usize) -> bool {
+        !self.denied.contains(&class_id)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&class_id))
+    }
+
+    // Remap and filter sorted class probabilities into the 'count' most probable labels. The
+    // probabilities of classes sharing a label are summed, under the ID of the most probable one.
+    pub(super) fn apply(&self, results: Vec<InferenceResult>, count: usize) -> Vec<Prediction> {
+        let mut predictions: Vec<Prediction> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for InferenceResult(class_id, probability) in results {
+            if !self.keeps(class_id) {
+                continue;
+            }
+            let label = match self.labels.get(&class_id) {
+                Some(label) => label.clone(),
+                None => label(class_id),
+            };
+            match positions.get(&label) {
+                Some(&position) => predictions[position].probability += probability,
+                None => {
+                    positions.insert(label.clone(), predictions.len());
+                    predictions.push(Prediction {
+                        class_id,
+                        label,
+                        localized_label: None,
+                        probability,
+                    });
+                }
+            }
+        }
+        let total: f32 = predictions.iter().map(|p| p.probability).sum();
+        if total > 0. {
+            for prediction in &mut predictions {
+                prediction.probability /= total;
+            }
+        }
+        predictions.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+        predictions.truncate(count);
+        predictions
+    }
+}
+
+// The label map a request is served with: the one it names, the one of its model or ensemble when
+// it names none, or no label map at all when it names NO_LABEL_MAP.
+pub fn resolve(
+    name: Option<&str>,
+    served: &Served<'_>,
+) -> Result<Option<&'static LabelMap>, InferenceError> {
+    match name.or(served.label_map()) {
+        None | Some(NO_LABEL_MAP) => Ok(None),
+        Some(name) => LABEL_MAPS
+            .get(name)
+            .map(Some)
+            .ok_or_else(|| InferenceError::InvalidInput(format!("unknown label map: {}", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Map the classes 1 and 2 to the same label, and deny the class 3.
+    fn label_map(allowed: Option<&[usize]>) -> LabelMap {
+        LabelMap {
+            labels: [(1, "citrus".to_string()), (2, "citrus".to_string())].into(),
+            allowed: allowed.map(|classes| classes.iter().cloned().collect()),
+            denied: [3].into(),
+            fingerprint: String::new(),
+        }
+    }
+
+    fn results() -> Vec<InferenceResult> {
+        vec![
+            InferenceResult(3, 0.4),
+            InferenceResult(1, 0.3),
+            InferenceResult(2, 0.2),
+            InferenceResult(0, 0.1),
+        ]
+    }
+
+    fn reported(predictions: &[Prediction]) -> Vec<(usize, &str, f32)> {
+        predictions
+            .iter()
+            .map(|p| {
+                (
+                    p.class_id,
+                    p.label.as_str(),
+                    (p.probability * 1000.).round() / 1000.,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merges_classes_sharing_a_label() {
+        let predictions = label_map(None).apply(results(), 5);
+        // The denied class is dropped, and the merged one is reported under its best class
+        assert_eq!(
+            reported(&predictions),
+            [(1, "citrus", 0.833), (0, label(0).as_str(), 0.167)]
+        );
+        let total: f32 = predictions.iter().map(|p| p.probability).sum();
+        assert!((total - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn keeps_only_the_allowed_classes() {
+        let predictions = label_map(Some(&[0, 2])).apply(results(), 5);
+        assert_eq!(
+            reported(&predictions),
+            [(2, "citrus", 0.667), (0, label(0).as_str(), 0.333)]
+        );
+        let predictions = label_map(None).apply(results(), 1);
+        assert_eq!(reported(&predictions), [(1, "citrus", 0.833)]);
+    }
+}
@@ -0,0 +1,208 @@
+// Open-set recognition: a model always spreads its scores over the classes it knows, even for a
+// screenshot or a blank image. The scores are checked for signs that the image is out of the
+// distribution the model was trained on, and the result is flagged instead of trusted.
+use super::ensemble;
+use crate::config::{ModelOutput, OpenSet, OpenSetMethod, CONFIG};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Confident,
+    // The image is probably of a known class, but the top predictions should not be trusted
+    LowConfidence,
+    // The image is probably of none of the classes
+    Unknown,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Confident => write!(f, "confident"),
+            Verdict::LowConfidence => write!(f, "low confidence"),
+            Verdict::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// The out-of-distribution score of an image, and the verdict its thresholds give.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OpenSetCheck {
+    pub verdict: Verdict,
+    pub method: OpenSetMethod,
+    pub score: f32,
+}
+
+// Check the scores of an image, which are of the given kind, when the check is enabled.
+pub fn check(scores: &[f32], output: ModelOutput) -> Option<OpenSetCheck> {
+    let config = &CONFIG.open_set;
+    if !config.enabled {
+        return None;
+    }
+    Some(check_with(scores, output, config))
+}
+
+// Check the scores of an image against the thresholds of the given configuration.
+fn check_with(scores: &[f32], output: ModelOutput, config: &OpenSet) -> OpenSetCheck {
+    // The energy of probabilities is always 0, so they are checked by their maximum instead
+    let method = match (config.method, output) {
+        (OpenSetMethod::Energy, ModelOutput::Probabilities) => OpenSetMethod::MaxSoftmax,
+        (method, _) => method,
+    };
+    let score = match method {
+        OpenSetMethod::MaxSoftmax => ensemble::probabilities(scores, output)
+            .into_iter()
+            .fold(0., f32::max),
+        OpenSetMethod::Entropy => {
+            let entropy: f32 = ensemble::probabilities(scores, output)
+                .iter()
+                .filter(|p| **p > 0.)
+                .map(|p| -p * p.ln())
+                .sum();
+            (entropy / (scores.len().max(2) as f32).ln()).min(1.)
+        }
+        OpenSetMethod::Energy => {
+            // Subtract the largest logit first so that the exponentials cannot overflow
+            let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            let sum: f32 = scores.iter().map(|s| (s - max).exp()).sum();
+            -(max + sum.ln())
+        }
+    };
+
+    let (unknown, low_confidence) = thresholds(method, config);
+    let past = |threshold: f32| match method {
+        OpenSetMethod::MaxSoftmax => score < threshold,
+        OpenSetMethod::Entropy | OpenSetMethod::Energy => score > threshold,
+    };
+    let verdict = if past(unknown) {
+        Verdict::Unknown
+    } else if past(low_confidence) {
+        Verdict::LowConfidence
+    } else {
+        Verdict::Confident
+    };
+    OpenSetCheck {
+        verdict,
+        method,
+        score,
+    }
+}
+
+// The unknown and low confidence thresholds of a method: the configured ones for the configured
+// method, and otherwise its defaults.
+fn thresholds(method: OpenSetMethod, config: &OpenSet) -> (f32, f32) {
+    let (unknown, low_confidence) = match method {
+        OpenSetMethod::MaxSoftmax => (0.2, 0.5),
+        OpenSetMethod::Entropy => (0.8, 0.5),
+        OpenSetMethod::Energy => (-6., -9.),
+    };
+    if method != config.method {
+        return (unknown, low_confidence);
+    }
+    (
+        config.unknown.unwrap_or(unknown),
+        config.low_confidence.unwrap_or(low_confidence),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_set(
+        method: OpenSetMethod,
+        unknown: Option<f32>,
+        low_confidence: Option<f32>,
+    ) -> OpenSet {
+        OpenSet {
+            enabled: true,
+            method,
+            unknown,
+            low_confidence,
+        }
+    }
+
+    fn verdict(scores: &[f32], output: ModelOutput, config: &OpenSet) -> Verdict {
+        check_with(scores, output, config).verdict
+    }
+
+    #[test]
+    fn flags_low_maximum_probabilities() {
+        let config = open_set(OpenSetMethod::MaxSoftmax, None, None);
+        let probabilities = ModelOutput::Probabilities;
+        assert_eq!(
+            verdict(&[0.6, 0.4], probabilities, &config),
+            Verdict::Confident
+        );
+        // A score on a threshold is not past it
+        assert_eq!(
+            verdict(&[0.5, 0.5], probabilities, &config),
+            Verdict::Confident
+        );
+        assert_eq!(
+            verdict(&[0.2; 5], probabilities, &config),
+            Verdict::LowConfidence
+        );
+        assert_eq!(
+            verdict(&[0.1; 10], probabilities, &config),
+            Verdict::Unknown
+        );
+        // Logits are turned into probabilities first
+        let check = check_with(&[0.; 4], ModelOutput::Logits, &config);
+        assert_eq!(check.score, 0.25);
+        assert_eq!(check.verdict, Verdict::LowConfidence);
+    }
+
+    #[test]
+    fn flags_high_entropies() {
+        let config = open_set(OpenSetMethod::Entropy, Some(1.), Some(0.));
+        let probabilities = ModelOutput::Probabilities;
+        // A certain prediction has no entropy, which is not past a threshold of 0
+        let check = check_with(&[1., 0., 0., 0.], probabilities, &config);
+        assert_eq!(check.score, 0.);
+        assert_eq!(check.verdict, Verdict::Confident);
+        // A uniform distribution has the highest entropy, scaled to 1 at most
+        let check = check_with(&[0.25; 4], probabilities, &config);
+        assert!((check.score - 1.).abs() < 1e-6 && check.score <= 1.);
+        assert_eq!(check.verdict, Verdict::LowConfidence);
+
+        let defaults = open_set(OpenSetMethod::Entropy, None, None);
+        assert_eq!(
+            verdict(&[0.25; 4], probabilities, &defaults),
+            Verdict::Unknown
+        );
+        assert_eq!(
+            verdict(&[0.9, 0.1], probabilities, &defaults),
+            Verdict::Confident
+        );
+    }
+
+    #[test]
+    fn flags_high_energies() {
+        let config = open_set(OpenSetMethod::Energy, None, None);
+        let check = check_with(&[10., 0., 0.], ModelOutput::Logits, &config);
+        assert!((check.score + 10.).abs() < 1e-3);
+        assert_eq!(check.verdict, Verdict::Confident);
+        // The energy of n equal logits is -ln(n)
+        assert_eq!(
+            verdict(&[0.; 1000], ModelOutput::Logits, &config),
+            Verdict::LowConfidence
+        );
+        assert_eq!(
+            verdict(&[0.; 100], ModelOutput::Logits, &config),
+            Verdict::Unknown
+        );
+    }
+
+    #[test]
+    fn checks_probabilities_by_their_maximum_instead_of_their_energy() {
+        // The energy thresholds do not apply to the maximum probability
+        let config = open_set(OpenSetMethod::Energy, Some(0.9), Some(0.95));
+        let check = check_with(&[0.6, 0.4], ModelOutput::Probabilities, &config);
+        assert_eq!(check.method, OpenSetMethod::MaxSoftmax);
+        assert_eq!(check.verdict, Verdict::Confident);
+        assert_eq!(thresholds(OpenSetMethod::Energy, &config), (0.9, 0.95));
+        assert_eq!(thresholds(OpenSetMethod::MaxSoftmax, &config), (0.2, 0.5));
+    }
+}
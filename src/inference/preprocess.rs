@@ -0,0 +1,150 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use std::io::Cursor;
+
+// Read the EXIF orientation tag of an encoded image. Images without EXIF data, or with an
+// unreadable tag, are reported as upright (orientation 1).
+pub fn exif_orientation(buf: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(buf))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+// Rotate and flip an image so that it is displayed upright, according to its EXIF orientation.
+// Orientations 5 and 7 are the transposes of 6 and 8, so they combine a rotation with a flip.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+// Convert an image to RGB. Images with an alpha channel are composited onto the background
+// color instead of having the channel dropped, which would expose whatever color values the
+// encoder left behind the transparent pixels (often black).
+pub fn flatten_alpha(img: &DynamicImage, background: [u8; 3]) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        let alpha = pixel[3] as f32 / 255.;
+        let mut rgb = [0u8; 3];
+        for c in 0..3 {
+            let blended = pixel[c] as f32 * alpha + background[c] as f32 * (1. - alpha);
+            rgb[c] = blended.round() as u8;
+        }
+        Rgb(rgb)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgba};
+
+    static BANANA: &[u8] = include_bytes!("../../test/banana.jpg");
+    static LEMON: &[u8] = include_bytes!("../../test/lemon.png");
+
+    #[test]
+    fn flattens_transparent_pixels_onto_the_background() {
+        // The lemon has an alpha channel but no transparent pixels, so its white backdrop is made
+        // transparent, with black behind it as encoders often leave it
+        let lemon = image::load_from_memory(LEMON).unwrap();
+        assert!(lemon.color().has_alpha());
+        let mut rgba = lemon.to_rgba8();
+        let mut transparent = Vec::new();
+        for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+            if pixel.0 == [255, 255, 255, 255] {
+                *pixel = Rgba([0, 0, 0, 0]);
+                transparent.push((x, y));
+            }
+        }
+        assert!(!transparent.is_empty() && transparent.len() < 500 * 500);
+        // The first of them, in the top-left corner, is made translucent instead
+        assert_eq!(transparent[0], (0, 0));
+        rgba.put_pixel(0, 0, Rgba([0, 0, 0, 128]));
+        let lemon = DynamicImage::ImageRgba8(rgba.clone());
+
+        for background in [[255, 255, 255], [0, 128, 255]] {
+            let flat = flatten_alpha(&lemon, background);
+            assert_eq!(flat.dimensions(), rgba.dimensions());
+            for &(x, y) in &transparent[1..] {
+                assert_eq!(flat.get_pixel(x, y).0, background);
+            }
+            // Translucent pixels are blended, and opaque pixels keep their color
+            let half = background.map(|c| (c as f32 * 127. / 255.).round() as u8);
+            assert_eq!(flat.get_pixel(0, 0).0, half);
+            let (x, y, opaque) = rgba
+                .enumerate_pixels()
+                .find(|(_, _, p)| p[3] == 255)
+                .unwrap();
+            assert_eq!(flat.get_pixel(x, y).0, [opaque[0], opaque[1], opaque[2]]);
+        }
+    }
+
+    #[test]
+    fn keeps_images_without_alpha() {
+        let banana = image::load_from_memory(BANANA).unwrap();
+        assert_eq!(flatten_alpha(&banana, [255, 0, 0]), banana.to_rgb8());
+    }
+
+    #[test]
+    fn applies_every_exif_orientation() {
+        // Mark the corners of the banana, so that where they end up tells the transforms apart
+        let mut banana = image::load_from_memory(BANANA).unwrap().to_rgba8();
+        let (w, h) = banana.dimensions();
+        assert_ne!(w, h);
+        let red = Rgba([255, 0, 0, 255]);
+        let green = Rgba([0, 255, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let yellow = Rgba([255, 255, 0, 255]);
+        banana.put_pixel(0, 0, red);
+        banana.put_pixel(w - 1, 0, green);
+        banana.put_pixel(0, h - 1, blue);
+        banana.put_pixel(w - 1, h - 1, yellow);
+        let banana = DynamicImage::ImageRgba8(banana);
+
+        // The top-left, top-right, bottom-left and bottom-right corners of the upright image
+        let expected = [
+            (1, [red, green, blue, yellow]),
+            (2, [green, red, yellow, blue]),
+            (3, [yellow, blue, green, red]),
+            (4, [blue, yellow, red, green]),
+            (5, [red, blue, green, yellow]),
+            (6, [blue, red, yellow, green]),
+            (7, [yellow, green, blue, red]),
+            (8, [green, yellow, red, blue]),
+        ];
+        for (orientation, corners) in expected {
+            let upright = apply_orientation(banana.clone(), orientation);
+            let (uw, uh) = upright.dimensions();
+            let swapped = orientation >= 5;
+            assert_eq!((uw, uh), if swapped { (h, w) } else { (w, h) });
+            let actual = [
+                upright.get_pixel(0, 0),
+                upright.get_pixel(uw - 1, 0),
+                upright.get_pixel(0, uh - 1),
+                upright.get_pixel(uw - 1, uh - 1),
+            ];
+            assert_eq!(actual, corners, "orientation {}", orientation);
+        }
+    }
+
+    #[test]
+    fn reads_upright_orientation_without_exif() {
+        assert_eq!(exif_orientation(LEMON), 1);
+        assert_eq!(exif_orientation(b"not an image"), 1);
+    }
+}
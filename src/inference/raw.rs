@@ -0,0 +1,102 @@
+use super::{image_to_tensor, internal_error, run_tensor, upright_image};
+use super::{InferenceError, Model};
+
+// Check the shape of an input tensor against the input signature of a model: a batch of images,
+// [batch, 3, height, width], or a single image, [3, height, width]. Returns the batch size.
+pub fn check_input_shape(model: &Model, shape: &[usize]) -> Result<usize, InferenceError> {
+    let image = [3, model.input_height as usize, model.input_width as usize];
+    match shape {
+        [batch, rest @ ..] if rest == image && *batch > 0 => Ok(*batch),
+        _ if shape == image => Ok(1),
+        _ => Err(InferenceError::InvalidInput(format!(
+            "model {} expects a tensor of shape [batch, {}, {}, {}], got {:?}",
+            model.name, image[0], image[1], image[2], shape
+        ))),
+    }
+}
+
+// Check that the values of a tensor sent by a client are all finite numbers, since a NaN or an
+// infinity would only come out of the model as meaningless scores.
+pub fn check_finite(values: &[f32]) -> Result<(), InferenceError> {
+    match values.iter().position(|v| !v.is_finite()) {
+        Some(index) => Err(InferenceError::InvalidInput(format!(
+            "the tensor holds a non-finite value ({}) at index {}",
+            values[index], index
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Run a batch of preprocessed images through a model, one at a time, and return the output
+// scores of each. The data holds the FP32 values of the [batch, 3, height, width] tensor, already
+// normalized the way the model expects.
+pub fn tensor_scores(
+    model: &Model,
+    data: &[f32],
+    batch: usize,
+) -> Result<Vec<Vec<f32>>, InferenceError> {
+    let image_size = 3 * model.input_height as usize * model.input_width as usize;
+    if data.len() != batch * image_size {
+        return Err(InferenceError::InvalidInput(format!(
+            "expected {} values for a batch of {}, got {}",
+            batch * image_size,
+            batch,
+            data.len()
+        )));
+    }
+    let graph = model.load_graph()?;
+    let mut context = graph.init_execution_context().map_err(internal_error)?;
+    data.chunks(image_size)
+        .map(|image| {
+            let tensor_data: Vec<u8> = image.iter().flat_map(|v| v.to_ne_bytes()).collect();
+            run_tensor(model, &mut context, &tensor_data)
+        })
+        .collect()
+}
+
+// Run a batch of encoded images through a model, with the same preprocessing as a classification
+// request, and return the output scores of each.
+pub fn image_scores(model: &Model, images: &[Vec<u8>]) -> Result<Vec<Vec<f32>>, InferenceError> {
+    let graph = model.load_graph()?;
+    let mut context = graph.init_execution_context().map_err(internal_error)?;
+    images
+        .iter()
+        .map(|image| {
+            let img = upright_image(image)?;
+            let tensor_data = image_to_tensor(&img, model.input_height, model.input_width);
+            run_tensor(model, &mut context, &tensor_data)
+        })
+        .collect()
+}
+
+// Feed a preprocessed [batch, 3, height, width] tensor to a model as-is, in a single call, and
+// return the raw output tensor of shape [batch, classes]. Unlike tensor_scores, models with a
+// fixed batch size fail on other batch sizes.
+pub fn run_batch(model: &Model, data: &[f32], batch: usize) -> Result<Vec<f32>, InferenceError> {
+    let dimensions = [
+        batch,
+        3,
+        model.input_height as usize,
+        model.input_width as usize,
+    ];
+    if data.len() != dimensions.iter().product::<usize>() {
+        return Err(InferenceError::InvalidInput(format!(
+            "expected {} values for a batch of {}, got {}",
+            dimensions.iter().product::<usize>(),
+            batch,
+            data.len()
+        )));
+    }
+    let graph = model.load_graph()?;
+    let mut context = graph.init_execution_context().map_err(internal_error)?;
+    let tensor_data: Vec<u8> = data.iter().flat_map(|v| v.to_ne_bytes()).collect();
+    context
+        .set_input(0, wasi_nn::TensorType::F32, &dimensions, &tensor_data)
+        .map_err(internal_error)?;
+    context.compute().map_err(internal_error)?;
+    let mut output_buffer = vec![0f32; model.classes * batch];
+    context
+        .get_output(0, &mut output_buffer)
+        .map_err(internal_error)?;
+    Ok(output_buffer)
+}
@@ -0,0 +1,298 @@
+use super::{content_hash, internal_error, InferenceError};
+use crate::config::{
+    CombineRule, Config, ModelConfig, ModelEncoding, ModelOutput, ModelTarget, CONFIG,
+};
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::fs;
+
+// Name under which the embedded MobileNet model is served
+pub const EMBEDDED_MODEL_NAME: &str = "mobilenet";
+
+// Define a lazy-static variable to store the models and ensembles that can be served
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::from_config(&CONFIG);
+}
+
+// A model that can be loaded into a wasi-nn graph.
+#[derive(Debug)]
+pub struct Model {
+    pub name: String,
+    pub encoding: ModelEncoding,
+    target: ModelTarget,
+    data: Vec<Cow<'static, [u8]>>,
+    pub input_width: u32,
+    pub input_height: u32,
+    pub classes: usize,
+    pub output: ModelOutput,
+    // Name of the label map applied to its results when a request does not pick one
+    pub label_map: Option<String>,
+    // Hash of the model files, which changes whenever the model is replaced by another version
+    pub fingerprint: String,
+}
+
+// Hash the files of a model into its fingerprint.
+fn fingerprint(data: &[Cow<'static, [u8]>]) -> String {
+    data.iter()
+        .map(|d| content_hash(d))
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+impl Model {
+    // The MobileNet model built into the binary.
+    fn embedded() -> Model {
+        let model_data = include_bytes!("../models/mobilenet.pt");
+        let data = vec![Cow::Borrowed(&model_data[..])];
+        Model {
+            name: EMBEDDED_MODEL_NAME.to_string(),
+            encoding: ModelEncoding::Pytorch,
+            target: ModelTarget::Cpu,
+            fingerprint: fingerprint(&data),
+            data,
+            input_width: 224,
+            input_height: 224,
+            classes: 1000,
+            output: ModelOutput::Logits,
+            label_map: None,
+        }
+    }
+
+    // Read the model files of a configured model.
+    fn from_config(config: &ModelConfig) -> Model {
+        if config.files.is_empty() {
+            panic!("Model {} does not list any model files", config.name);
+        }
+        let data: Vec<Cow<'static, [u8]>> = config
+            .files
+            .iter()
+            .map(|path| match fs::read(path) {
+                Ok(bytes) => Cow::Owned(bytes),
+                Err(e) => panic!("Failed to read model file {}: {}", path, e),
+            })
+            .collect();
+        Model {
+            name: config.name.clone(),
+            encoding: config.encoding,
+            target: config.target,
+            fingerprint: fingerprint(&data),
+            data,
+            input_width: config.input_width,
+            input_height: config.input_height,
+            classes: config.classes,
+            output: config.output,
+            label_map: config.label_map.clone(),
+        }
+    }
+
+    // Build a wasi-nn graph from the model files.
+    pub fn load_graph(&self) -> Result<wasi_nn::Graph, InferenceError> {
+        eprintln!(
+            "Using {:?} model {}, size in bytes: {}",
+            self.encoding,
+            self.name,
+            self.data.iter().map(|d| d.len()).sum::<usize>(),
+        );
+        let encoding = match self.encoding {
+            ModelEncoding::Openvino => wasi_nn::GraphEncoding::Openvino,
+            ModelEncoding::Onnx => wasi_nn::GraphEncoding::Onnx,
+            ModelEncoding::Tensorflow => wasi_nn::GraphEncoding::Tensorflow,
+            ModelEncoding::Pytorch => wasi_nn::GraphEncoding::Pytorch,
+            ModelEncoding::Tensorflowlite => wasi_nn::GraphEncoding::TensorflowLite,
+        };
+        let target = match self.target {
+            ModelTarget::Cpu => wasi_nn::ExecutionTarget::CPU,
+            ModelTarget::Gpu => wasi_nn::ExecutionTarget::GPU,
+            ModelTarget::Tpu => wasi_nn::ExecutionTarget::TPU,
+        };
+        let graph = wasi_nn::GraphBuilder::new(encoding, target)
+            .build_from_bytes(&self.data)
+            .map_err(internal_error)?;
+        eprintln!("Loaded graph into wasi-nn with ID: {:?}", graph);
+        Ok(graph)
+    }
+}
+
+// A virtual model combining the scores of several models.
+#[derive(Debug)]
+pub struct Ensemble {
+    pub name: String,
+    // Indexes of the member models in the registry, with their weights
+    members: Vec<(usize, f32)>,
+    pub combine: CombineRule,
+    pub label_map: Option<String>,
+}
+
+// A model or an ensemble, as picked by name in a request.
+pub enum Served<'a> {
+    Model(&'a Model),
+    Ensemble(&'a Ensemble),
+}
+
+impl Served<'_> {
+    // Name of the label map applied to the results of the model or ensemble by default.
+    pub fn label_map(&self) -> Option<&str> {
+        match self {
+            Served::Model(model) => model.label_map.as_deref(),
+            Served::Ensemble(ensemble) => ensemble.label_map.as_deref(),
+        }
+    }
+}
+
+// The models and ensembles that can be served, by name.
+#[derive(Debug)]
+pub struct Registry {
+    models: Vec<Model>,
+    ensembles: Vec<Ensemble>,
+    default_name: String,
+}
+
+impl Registry {
+    // Load the configured models and check the ensembles. Like an invalid configuration file,
+    // an invalid registry aborts at startup.
+    fn from_config(config: &Config) -> Registry {
+        let mut models = vec![Model::embedded()];
+        for model_config in &config.models {
+            let model = Model::from_config(model_config);
+            // A configured model replaces the embedded one when it uses the same name
+            models.retain(|m| m.name != model.name);
+            models.push(model);
+        }
+
+        let mut ensembles = Vec::new();
+        for ensemble_config in &config.ensembles {
+            let name = &ensemble_config.name;
+            if models.iter().any(|m| &m.name == name)
+                || ensembles.iter().any(|e: &Ensemble| &e.name == name)
+            {
+                panic!("Ensemble {} has the same name as another model", name);
+            }
+            if ensemble_config.members.is_empty() {
+                panic!("Ensemble {} has no members", name);
+            }
+            let members: Vec<(usize, f32)> = ensemble_config
+                .members
+                .iter()
+                .map(|member| {
+                    let index = models
+                        .iter()
+                        .position(|m| m.name == member.model)
+                        .unwrap_or_else(|| {
+                            panic!("Ensemble {} uses unknown model {}", name, member.model)
+                        });
+                    // Negated so that a NaN weight, which compares false, is rejected too
+                    if !(member.weight.is_finite() && member.weight > 0.) {
+                        panic!(
+                            "Ensemble {} gives model {} a weight that is not a positive number",
+                            name, member.model
+                        );
+                    }
+                    (index, member.weight)
+                })
+                .collect();
+            let classes = models[members[0].0].classes;
+            if members
+                .iter()
+                .any(|(index, _)| models[*index].classes != classes)
+            {
+                panic!(
+                    "The members of ensemble {} have different label spaces",
+                    name
+                );
+            }
+            ensembles.push(Ensemble {
+                name: name.clone(),
+                members,
+                combine: ensemble_config.combine,
+                label_map: ensemble_config.label_map.clone(),
+            });
+        }
+
+        let registry = Registry {
+            models,
+            ensembles,
+            default_name: config
+                .default_model
+                .clone()
+                .unwrap_or_else(|| EMBEDDED_MODEL_NAME.to_string()),
+        };
+        if registry.get(&registry.default_name).is_none() {
+            panic!("Default model {} is not defined", registry.default_name);
+        }
+        let label_maps = registry
+            .models
+            .iter()
+            .map(|m| (&m.name, &m.label_map))
+            .chain(registry.ensembles.iter().map(|e| (&e.name, &e.label_map)));
+        for (name, label_map) in label_maps {
+            if let Some(label_map) = label_map {
+                if !config.label_maps.contains_key(label_map) {
+                    panic!("Model {} uses unknown label map {}", name, label_map);
+                }
+            }
+        }
+        registry
+    }
+
+    // Look up a model or an ensemble by name.
+    pub fn get(&self, name: &str) -> Option<Served<'_>> {
+        if let Some(model) = self.models.iter().find(|m| m.name == name) {
+            return Some(Served::Model(model));
+        }
+        self.ensembles
+            .iter()
+            .find(|e| e.name == name)
+            .map(Served::Ensemble)
+    }
+
+    // Names of the models and then of the ensembles, in the order of the configuration.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.models
+            .iter()
+            .map(|m| m.name.as_str())
+            .chain(self.ensembles.iter().map(|e| e.name.as_str()))
+    }
+
+    // Name of the model or ensemble served when a request does not pick one.
+    pub fn default_name(&self) -> &str {
+        &self.default_name
+    }
+
+    // Look up the model or ensemble requested by name, or the default one.
+    pub fn resolve(&self, name: Option<&str>) -> Result<Served<'_>, InferenceError> {
+        let name = name.unwrap_or(&self.default_name);
+        self.get(name)
+            .ok_or_else(|| InferenceError::UnknownModel(name.to_string()))
+    }
+
+    // Identify exactly what serves a model or an ensemble: the versions of its models, and the
+    // weights and combine rule of an ensemble.
+    pub fn fingerprint(&self, served: &Served<'_>) -> String {
+        let members: Vec<String> = self
+            .members(served)
+            .iter()
+            .map(|(model, weight)| format!("{}@{}*{}", model.name, model.fingerprint, weight))
+            .collect();
+        match served {
+            Served::Model(_) => members.join(","),
+            Served::Ensemble(ensemble) => format!(
+                "{}[{}]{:?}",
+                ensemble.name,
+                members.join(","),
+                ensemble.combine
+            ),
+        }
+    }
+
+    // The models that are run to serve a model or an ensemble, with their weights.
+    pub fn members<'a>(&'a self, served: &Served<'a>) -> Vec<(&'a Model, f32)> {
+        match served {
+            Served::Model(model) => vec![(*model, 1.)],
+            Served::Ensemble(ensemble) => ensemble
+                .members
+                .iter()
+                .map(|(index, weight)| (&self.models[*index], *weight))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+// The image classification pipeline, shared by the web server and the command line tools.
+pub mod assets;
+pub mod cache;
+pub mod comparison;
+pub mod config;
+pub mod evaluation;
+pub mod feedback;
+pub mod history;
+pub mod images;
+pub mod inference;
+pub mod locale;
+pub mod oip;
+pub mod payload;
+pub mod rollout;
+pub mod templates;
+pub mod tensor;
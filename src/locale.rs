@@ -0,0 +1,254 @@
+// Localized web pages and labels. The strings of the pages are built in for a few languages, and
+// deployments add label tables and more languages as files in the locales directory. The built-in
+// label tables only cover the food classes of ImageNet. English is the fallback of every missing
+// string and label.
+use crate::config::CONFIG;
+use crate::inference::{find_class, label, Classification, Prediction};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use tera::Context;
+
+// The language served when a request asks for none that is available
+pub const DEFAULT_LANG: &str = "en";
+
+// Define static variables for the built-in strings of the pages, by language
+static BUILT_IN: [(&str, &str); 4] = [
+    ("en", include_str!("locales/en.toml")),
+    ("fr", include_str!("locales/fr.toml")),
+    ("de", include_str!("locales/de.toml")),
+    ("es", include_str!("locales/es.toml")),
+];
+
+// Define a lazy-static variable to store the available locales, by lowercase language tag
+lazy_static! {
+    pub static ref LOCALES: HashMap<String, Locale> = load_locales();
+}
+
+// A locale file. Labels are keyed by class ID or by one of the names of a class, or by any other
+// label the server reports, such as those of label maps and hierarchy categories.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LocaleFile {
+    labels: BTreeMap<String, String>,
+    ui: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct Locale {
+    pub lang: String,
+    // The strings of the pages, complete with the English ones it lacks
+    ui: HashMap<String, String>,
+    // Translated labels, by canonical label
+    labels: HashMap<String, String>,
+}
+
+impl Locale {
+    fn new(lang: &str) -> Locale {
+        Locale {
+            lang: lang.to_string(),
+            ui: HashMap::new(),
+            labels: HashMap::new(),
+        }
+    }
+
+    fn merge(&mut self, file: LocaleFile) {
+        self.ui.extend(file.ui);
+        for (key, translation) in file.labels {
+            let canonical = match find_class(&key) {
+                Some(class_id) => label(class_id),
+                None => key,
+            };
+            self.labels.insert(canonical, translation);
+        }
+    }
+
+    // The translation of a label, if the locale has one.
+    pub fn label(&self, canonical: &str) -> Option<&str> {
+        self.labels.get(canonical).map(String::as_str)
+    }
+
+    // Add the localized label to every prediction and category of a classification, the canonical
+    // one when it has no translation.
+    pub fn localize(&self, classification: &mut Classification) {
+        let localize = |predictions: &mut Vec<Prediction>| {
+            for prediction in predictions {
+                let localized = self.label(&prediction.label).unwrap_or(&prediction.label);
+                prediction.localized_label = Some(localized.to_string());
+            }
+        };
+        localize(&mut classification.predictions);
+        for frame in &mut classification.frames {
+            localize(&mut frame.predictions);
+        }
+        for member in &mut classification.members {
+            localize(&mut member.predictions);
+        }
+        for category in &mut classification.categories {
+            let localized = self.label(&category.category).unwrap_or(&category.category);
+            category.localized_label = Some(localized.to_string());
+        }
+    }
+
+    // A template context holding the language and the strings of the pages, as "lang" and "t".
+    pub fn context(&self) -> Context {
+        let mut context = Context::new();
+        context.insert("lang", &self.lang);
+        context.insert("t", &self.ui);
+        context
+    }
+}
+
+// Load the built-in locales, and merge the files of the locales directory over them. An invalid
+// file is a deployment error, so it aborts at startup.
+fn load_locales() -> HashMap<String, Locale> {
+    let mut locales: HashMap<String, Locale> = HashMap::new();
+    for (lang, contents) in BUILT_IN {
+        let file: LocaleFile = toml::from_str(contents)
+            .unwrap_or_else(|e| panic!("Invalid built-in locale {}: {}", lang, e));
+        locales
+            .entry(lang.to_string())
+            .or_insert_with(|| Locale::new(lang))
+            .merge(file);
+    }
+
+    let dir = Path::new(&CONFIG.locales.dir);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.collect::<Result<Vec<_>, _>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+    .unwrap_or_else(|e| panic!("Failed to read locales {}: {}", dir.display(), e));
+    for entry in entries {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+        let Some(lang) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        let lang = lang.to_lowercase();
+        let file: LocaleFile = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| toml::from_str(&contents).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| panic!("Invalid locale {}: {}", path.display(), e));
+        locales
+            .entry(lang.clone())
+            .or_insert_with(|| Locale::new(&lang))
+            .merge(file);
+        eprintln!("Loaded locale {} from: {}", lang, path.display());
+    }
+
+    // Fill in the strings that a language lacks with the English ones
+    let english = locales[DEFAULT_LANG].ui.clone();
+    for locale in locales.values_mut() {
+        for (key, value) in &english {
+            locale
+                .ui
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+    locales
+}
+
+// Pick the locale of a request: the one named by its lang parameter, or else the most preferred
+// one of its Accept-Language header, or else English. A regional tag such as "fr-CA" falls back to
+// its language when the region has no locale of its own.
+pub fn negotiate(lang: Option<&str>, accept_language: Option<&str>) -> &'static Locale {
+    let mut preferences: Vec<(String, f32)> = Vec::new();
+    if let Some(lang) = lang {
+        preferences.push((lang.to_string(), f32::INFINITY));
+    }
+    for range in accept_language.unwrap_or("").split(',') {
+        let mut parts = range.split(';');
+        let tag = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.);
+        if !tag.is_empty() && tag != "*" && quality > 0. {
+            preferences.push((tag.to_string(), quality));
+        }
+    }
+    // A stable sort keeps the order of the header among ranges of the same quality
+    preferences.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    preferences
+        .iter()
+        .find_map(|(tag, _)| {
+            let tag = tag.to_lowercase();
+            LOCALES.get(&tag).or_else(|| {
+                let (language, _) = tag.split_once('-')?;
+                LOCALES.get(language)
+            })
+        })
+        .unwrap_or(&LOCALES[DEFAULT_LANG])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_the_labels_of_known_classes() {
+        // 954 is banana and 951 is lemon
+        for (lang, banana, lemon) in [
+            ("fr", "banane", "citron"),
+            ("de", "Banane", "Zitrone"),
+            ("es", "plátano", "limón"),
+        ] {
+            let locale = &LOCALES[lang];
+            assert_eq!(locale.label(&label(954)), Some(banana), "{}", lang);
+            assert_eq!(locale.label(&label(951)), Some(lemon), "{}", lang);
+        }
+        assert_eq!(LOCALES[DEFAULT_LANG].label(&label(954)), None);
+    }
+
+    #[test]
+    fn localizes_predictions_and_falls_back_to_english() {
+        let locale = negotiate(None, Some("de-AT, en;q=0.5"));
+        assert_eq!(locale.lang, "de");
+        // 1 is goldfish, which has no built-in translation
+        let mut classification: Classification = serde_json::from_value(serde_json::json!({
+            "model": "test",
+            "predictions": [
+                { "class_id": 954, "label": label(954), "probability": 0.9 },
+                { "class_id": 1, "label": label(1), "probability": 0.1 },
+            ],
+            // Categories are translated by their name, like any other label
+            "categories": [
+                { "category": label(954), "probability": 0.9 },
+                { "category": "fruit", "probability": 0.9 },
+            ],
+        }))
+        .unwrap();
+        locale.localize(&mut classification);
+        let localized: Vec<_> = classification
+            .predictions
+            .iter()
+            .map(|p| p.localized_label.as_deref())
+            .collect();
+        assert_eq!(localized, [Some("Banane"), Some(label(1).as_str())]);
+        let localized: Vec<_> = classification
+            .categories
+            .iter()
+            .map(|c| c.localized_label.as_deref())
+            .collect();
+        assert_eq!(localized, [Some("Banane"), Some("fruit")]);
+    }
+
+    #[test]
+    fn built_in_labels_name_imagenet_classes() {
+        for (lang, contents) in BUILT_IN {
+            let file: LocaleFile = toml::from_str(contents).unwrap();
+            for key in file.labels.keys() {
+                let class_id = find_class(key);
+                assert!(class_id.is_some(), "{}: {} is not a class", lang, key);
+            }
+        }
+    }
+}
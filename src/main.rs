@@ -1,17 +1,53 @@
 use std::net::SocketAddr;
 use warp::Filter;
-mod inference;
+use wasm_ai_demo_app::{
+    assets, cache, comparison, config, feedback, history, images, inference, locale, oip, payload,
+    rollout, templates, tensor,
+};
 mod routes;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
 
-    // Combine the routes from the routes module
-    let routes = routes::root()
+    // Load the configuration and the models up front so that invalid ones fail at startup
+    lazy_static::initialize(&config::CONFIG);
+    lazy_static::initialize(&inference::REGISTRY);
+    lazy_static::initialize(&rollout::ROLLOUT);
+    lazy_static::initialize(&inference::hierarchy::HIERARCHY);
+    lazy_static::initialize(&inference::labels::LABEL_MAPS);
+    lazy_static::initialize(&locale::LOCALES);
+    lazy_static::initialize(&routes::SHADOW_RUNS);
+
+    // Combine the routes from the routes module. The web pages report rejected requests as HTML
+    // and the APIs as JSON
+    let pages = routes::root()
         .or(routes::inference())
+        .or(routes::history())
+        .or(routes::image_rendition())
+        .or(routes::feedback())
         .or(routes::upload())
-        .or(routes::not_found());
+        .or(routes::compare_form())
+        .or(routes::compare())
+        .or(routes::static_asset())
+        .recover(routes::handle_rejection);
+    let apis = routes::api_inference()
+        .or(routes::api_history())
+        .or(routes::api_feedback_export())
+        .or(routes::api_feedback())
+        .or(routes::api_image_annotated())
+        .or(routes::api_admin_cache())
+        .or(routes::api_admin_rollout())
+        .or(routes::api_tensor())
+        .or(routes::oip_server())
+        .or(routes::oip_health_live())
+        .or(routes::oip_health_ready())
+        .or(routes::oip_model_metadata())
+        .or(routes::oip_model_ready())
+        .or(routes::oip_infer())
+        .or(routes::api_compare())
+        .recover(routes::handle_api_rejection);
+    let routes = pages.or(apis).or(routes::not_found());
 
     println!("Listening on http://{}/", addr);
 
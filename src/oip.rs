@@ -0,0 +1,457 @@
+// The Open Inference Protocol (version 2 of the KServe protocol, also spoken by Triton), on top of
+// the model registry. Every model has a single input tensor and a single output tensor.
+use crate::config::ModelEncoding;
+use crate::inference::{self, InferenceError, Model, Served, REGISTRY};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Names of the input and output tensors of every model
+pub const INPUT_NAME: &str = "input";
+pub const OUTPUT_NAME: &str = "output";
+
+#[derive(Debug, Serialize)]
+pub struct ServerMetadata {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub extensions: Vec<&'static str>,
+}
+
+pub fn server_metadata() -> ServerMetadata {
+    ServerMetadata {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        extensions: vec!["classification", "binary_tensor_data"],
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelMetadata {
+    pub name: String,
+    pub versions: Vec<String>,
+    pub platform: ModelEncoding,
+    pub inputs: Vec<TensorMetadata>,
+    pub outputs: Vec<TensorMetadata>,
+}
+
+// The name, type and shape of a tensor, where -1 stands for a dimension of any size.
+#[derive(Debug, Serialize)]
+pub struct TensorMetadata {
+    pub name: &'static str,
+    pub datatype: &'static str,
+    pub shape: Vec<i64>,
+}
+
+// Look up a model served over the protocol. Ensembles are not, since their members may take
+// inputs of different sizes.
+pub fn model(name: &str) -> Result<&'static Model, InferenceError> {
+    match REGISTRY.get(name) {
+        Some(Served::Model(model)) => Ok(model),
+        _ => Err(InferenceError::UnknownModel(name.to_string())),
+    }
+}
+
+// Describe the input and output tensors of a model. The version of a model is its fingerprint.
+pub fn model_metadata(model: &Model) -> ModelMetadata {
+    ModelMetadata {
+        name: model.name.clone(),
+        versions: vec![model.fingerprint.clone()],
+        platform: model.encoding,
+        inputs: vec![TensorMetadata {
+            name: INPUT_NAME,
+            datatype: "FP32",
+            shape: vec![-1, 3, model.input_height as i64, model.input_width as i64],
+        }],
+        outputs: vec![TensorMetadata {
+            name: OUTPUT_NAME,
+            datatype: "FP32",
+            shape: vec![-1, model.classes as i64],
+        }],
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InferRequest {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub parameters: Parameters,
+    pub inputs: Vec<RequestInput>,
+    // The outputs to return; every output when empty
+    #[serde(default)]
+    pub outputs: Vec<RequestOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestInput {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub datatype: String,
+    #[serde(default)]
+    pub parameters: Parameters,
+    // The values, unless they are sent as binary data after the JSON header
+    #[serde(default)]
+    pub data: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestOutput {
+    pub name: String,
+    #[serde(default)]
+    pub parameters: Parameters,
+}
+
+// The parameters of the binary data and classification extensions, of the request, its inputs or
+// its outputs. Other parameters are ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Parameters {
+    // Size of the input in the binary data that follows the JSON header
+    pub binary_data_size: Option<usize>,
+    // Of the request: return every output as binary data unless the output says otherwise
+    pub binary_data_output: Option<bool>,
+    // Of an output: return it as binary data after the JSON header, or in the JSON
+    pub binary_data: Option<bool>,
+    // Return the given number of best classes instead of the raw output tensor
+    pub classification: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InferResponse {
+    pub model_name: String,
+    pub model_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub outputs: Vec<ResponseOutput>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponseOutput {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub datatype: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<OutputParameters>,
+    // The values, unless they are sent as binary data after the JSON header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(skip)]
+    pub binary_data: Option<Vec<u8>>,
+}
+
+// The size of an output sent as binary data after the JSON header.
+#[derive(Debug, Serialize)]
+pub struct OutputParameters {
+    pub binary_data_size: usize,
+}
+
+impl InferResponse {
+    // Encode the response body: the JSON response, followed by the outputs sent as binary data, in
+    // order. When there are any, the length of the JSON part is also returned, for the
+    // Inference-Header-Content-Length header.
+    pub fn encode(&self) -> Result<(Vec<u8>, Option<usize>), InferenceError> {
+        let mut body = serde_json::to_vec(self)
+            .map_err(|e| InferenceError::Internal(format!("failed to encode response: {}", e)))?;
+        let header_length = body.len();
+        let mut binary = false;
+        for data in self.outputs.iter().filter_map(|o| o.binary_data.as_ref()) {
+            body.extend_from_slice(data);
+            binary = true;
+        }
+        Ok((body, binary.then_some(header_length)))
+    }
+}
+
+// The values of an output, before they are encoded in the JSON or as binary data.
+enum OutputValues {
+    Fp32(Vec<f32>),
+    Bytes(Vec<String>),
+}
+
+impl OutputValues {
+    // Encode the values in the JSON, or as binary data: little-endian FP32 values, or BYTES
+    // elements prefixed with their 4-byte little-endian length.
+    fn encode(self, binary: bool) -> (Option<Value>, Option<Vec<u8>>) {
+        if !binary {
+            let data = match self {
+                OutputValues::Fp32(values) => values.into(),
+                OutputValues::Bytes(elements) => elements.into(),
+            };
+            return (Some(data), None);
+        }
+        let data = match self {
+            OutputValues::Fp32(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            OutputValues::Bytes(elements) => elements
+                .iter()
+                .flat_map(|e| {
+                    let length = (e.len() as u32).to_le_bytes();
+                    length.into_iter().chain(e.bytes())
+                })
+                .collect(),
+        };
+        (None, Some(data))
+    }
+}
+
+// The decoded input of an inference request.
+enum Input {
+    // Preprocessed FP32 images, with the batch size
+    Tensor(Vec<f32>, usize),
+    // Encoded images, preprocessed like a classification request
+    Images(Vec<Vec<u8>>),
+}
+
+// Run an inference request on a model. The body is a JSON request, optionally followed by binary
+// input data when 'header_length' gives the size of the JSON part, or a bare encoded image when
+// its content type is an image type.
+pub fn infer(
+    model: &Model,
+    body: &[u8],
+    header_length: Option<usize>,
+    content_type: Option<&str>,
+) -> Result<InferResponse, InferenceError> {
+    let bare_image = content_type
+        .is_some_and(|t| t.starts_with("image/") || t.starts_with("application/octet-stream"));
+    let (request, input) = if header_length.is_none() && bare_image {
+        let request = InferRequest {
+            id: None,
+            parameters: Parameters::default(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        };
+        (request, Input::Images(vec![body.to_vec()]))
+    } else {
+        let header_length = header_length.unwrap_or(body.len());
+        if header_length > body.len() {
+            return Err(invalid_input(
+                "the inference header is longer than the request body",
+            ));
+        }
+        let (header, binary) = body.split_at(header_length);
+        let request: InferRequest = serde_json::from_slice(header)
+            .map_err(|e| InferenceError::InvalidInput(format!("invalid request: {}", e)))?;
+        let input = decode_input(model, &request, binary)?;
+        (request, input)
+    };
+
+    let scores = match input {
+        Input::Tensor(values, batch) => inference::tensor_scores(model, &values, batch)?,
+        Input::Images(images) => inference::image_scores(model, &images)?,
+    };
+
+    let default_output = [RequestOutput {
+        name: OUTPUT_NAME.to_string(),
+        parameters: Parameters::default(),
+    }];
+    let requested = if request.outputs.is_empty() {
+        &default_output[..]
+    } else {
+        &request.outputs[..]
+    };
+    let outputs = requested
+        .iter()
+        .map(|output| {
+            if output.name != OUTPUT_NAME {
+                return Err(InferenceError::InvalidInput(format!(
+                    "unknown output: {}",
+                    output.name
+                )));
+            }
+            let (shape, datatype, values) = match output.parameters.classification {
+                // Classes are reported as "score:class ID:label" strings
+                Some(count) => {
+                    let count = count.clamp(1, model.classes);
+                    let classes = scores
+                        .iter()
+                        .flat_map(|s| inference::rank(s, model.output, count))
+                        .map(|p| format!("{}:{}:{}", p.probability, p.class_id, p.label))
+                        .collect();
+                    (
+                        vec![scores.len(), count],
+                        "BYTES",
+                        OutputValues::Bytes(classes),
+                    )
+                }
+                None => (
+                    vec![scores.len(), model.classes],
+                    "FP32",
+                    OutputValues::Fp32(scores.concat()),
+                ),
+            };
+            let binary = output
+                .parameters
+                .binary_data
+                .or(request.parameters.binary_data_output)
+                .unwrap_or(false);
+            let (data, binary_data) = values.encode(binary);
+            Ok(ResponseOutput {
+                name: output.name.clone(),
+                shape,
+                datatype,
+                parameters: binary_data.as_ref().map(|data| OutputParameters {
+                    binary_data_size: data.len(),
+                }),
+                data,
+                binary_data,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(InferResponse {
+        model_name: model.name.clone(),
+        model_version: model.fingerprint.clone(),
+        id: request.id,
+        outputs,
+    })
+}
+
+fn invalid_input(message: &str) -> InferenceError {
+    InferenceError::InvalidInput(message.to_string())
+}
+
+// Decode the single input of a request: an FP32 tensor of preprocessed images, or a BYTES tensor
+// of encoded images, from the JSON data or from the binary data.
+fn decode_input(
+    model: &Model,
+    request: &InferRequest,
+    binary: &[u8],
+) -> Result<Input, InferenceError> {
+    let [input] = &request.inputs[..] else {
+        return Err(InferenceError::InvalidInput(format!(
+            "expected a single input, got {}",
+            request.inputs.len()
+        )));
+    };
+    let count = input
+        .shape
+        .iter()
+        .try_fold(1usize, |count, dimension| count.checked_mul(*dimension))
+        .ok_or_else(|| invalid_input("the input shape holds too many elements"))?;
+    let binary = match input.parameters.binary_data_size {
+        Some(size) if size > binary.len() => {
+            return Err(invalid_input(
+                "binary_data_size is larger than the binary data",
+            ))
+        }
+        Some(size) => Some(&binary[..size]),
+        None => None,
+    };
+
+    let input = match input.datatype.as_str() {
+        "FP32" => {
+            let batch = inference::check_input_shape(model, &input.shape)?;
+            let values = match binary {
+                Some(bytes) if bytes.len() % 4 == 0 => bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+                Some(_) => {
+                    return Err(invalid_input(
+                        "FP32 binary data is not a multiple of 4 bytes",
+                    ))
+                }
+                None => {
+                    let mut values = Vec::new();
+                    flatten_numbers(input.data.as_ref(), &mut values)?;
+                    values
+                }
+            };
+            check_count(values.len(), count)?;
+            inference::check_finite(&values)?;
+            Input::Tensor(values, batch)
+        }
+        "BYTES" => {
+            let images = match binary {
+                Some(bytes) => split_bytes_elements(bytes)?,
+                None => {
+                    let mut images = Vec::new();
+                    flatten_base64(input.data.as_ref(), &mut images)?;
+                    images
+                }
+            };
+            check_count(images.len(), count)?;
+            Input::Images(images)
+        }
+        other => {
+            return Err(InferenceError::InvalidInput(format!(
+                "unsupported datatype {}, expected FP32 or BYTES",
+                other
+            )))
+        }
+    };
+    Ok(input)
+}
+
+fn check_count(actual: usize, expected: usize) -> Result<(), InferenceError> {
+    if actual != expected {
+        return Err(InferenceError::InvalidInput(format!(
+            "the input shape holds {} elements, but {} were given",
+            expected, actual
+        )));
+    }
+    Ok(())
+}
+
+// Collect the numbers of a JSON tensor, which may be flat or nested by dimension.
+fn flatten_numbers(data: Option<&Value>, values: &mut Vec<f32>) -> Result<(), InferenceError> {
+    match data {
+        Some(Value::Array(items)) => {
+            for item in items {
+                flatten_numbers(Some(item), values)?;
+            }
+            Ok(())
+        }
+        Some(Value::Number(n)) => {
+            values.push(n.as_f64().unwrap_or_default() as f32);
+            Ok(())
+        }
+        Some(_) => Err(invalid_input("FP32 data must only hold numbers")),
+        None => Err(invalid_input(
+            "the input has neither data nor binary_data_size",
+        )),
+    }
+}
+
+// Collect the elements of a JSON BYTES tensor, given as base64 strings since JSON cannot hold
+// binary data.
+fn flatten_base64(data: Option<&Value>, images: &mut Vec<Vec<u8>>) -> Result<(), InferenceError> {
+    match data {
+        Some(Value::Array(items)) => {
+            for item in items {
+                flatten_base64(Some(item), images)?;
+            }
+            Ok(())
+        }
+        Some(Value::String(encoded)) => {
+            let image = base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|e| InferenceError::InvalidInput(format!("invalid base64: {}", e)))?;
+            images.push(image);
+            Ok(())
+        }
+        Some(_) => Err(invalid_input("BYTES data must only hold base64 strings")),
+        None => Err(invalid_input(
+            "the input has neither data nor binary_data_size",
+        )),
+    }
+}
+
+// Split the binary data of a BYTES tensor into its elements, each of which is prefixed with its
+// length as a 4-byte little-endian integer.
+fn split_bytes_elements(mut bytes: &[u8]) -> Result<Vec<Vec<u8>>, InferenceError> {
+    let mut elements = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            return Err(invalid_input("truncated BYTES element length"));
+        }
+        let (length, rest) = bytes.split_at(4);
+        let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        if length > rest.len() {
+            return Err(invalid_input("truncated BYTES element"));
+        }
+        let (element, rest) = rest.split_at(length);
+        elements.push(element.to_vec());
+        bytes = rest;
+    }
+    Ok(elements)
+}
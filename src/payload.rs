@@ -0,0 +1,129 @@
+// JSON classification requests, for frontends that cannot send an image as the raw request body:
+// the images are given as base64 strings or data URLs, along with the options of the request.
+use crate::inference::explain::ExplainOptions;
+use crate::inference::{self, InferenceError, InferenceOptions};
+use base64::Engine;
+use serde::Deserialize;
+
+// A JSON classification request, e.g. {"image": "data:image/png;base64,...", "top_k": 3}.
+#[derive(Debug, Deserialize)]
+pub struct ImageRequest {
+    // One image, or an array of images
+    #[serde(alias = "images")]
+    pub image: ImagePayloads,
+    // The same options as the query string of a binary request
+    #[serde(flatten)]
+    pub options: InferenceOptions,
+    // Whether and how to explain the top class of each image
+    #[serde(flatten)]
+    pub explain: ExplainOptions,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ImagePayloads {
+    One(String),
+    Many(Vec<String>),
+}
+
+// An image decoded from a payload, with its MIME type as detected from its magic bytes.
+#[derive(Debug)]
+pub struct DecodedImage {
+    pub data: Vec<u8>,
+    pub mime: &'static str,
+}
+
+// Decode an image given as a data URL or as plain base64. The media type declared in a data URL
+// is ignored, since only the magic bytes of the image are trusted.
+pub fn decode_payload(payload: &str) -> Result<DecodedImage, InferenceError> {
+    let encoded = match payload.strip_prefix("data:") {
+        Some(url) => {
+            let (metadata, data) = url
+                .split_once(',')
+                .ok_or_else(|| InferenceError::InvalidImage("data URL has no data".to_string()))?;
+            if !metadata.ends_with(";base64") {
+                return Err(InferenceError::InvalidImage(
+                    "data URLs must be base64 encoded".to_string(),
+                ));
+            }
+            data
+        }
+        None => payload,
+    };
+    // Base64 from some encoders is wrapped over several lines
+    let encoded: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| InferenceError::InvalidImage(format!("invalid base64: {}", e)))?;
+    let mime = inference::mime_type(&data)
+        .ok_or_else(|| InferenceError::InvalidImage("unrecognized image format".to_string()))?;
+    Ok(DecodedImage { data, mime })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The signature of a PNG file, which is enough to recognize its format
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    fn encode(data: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(data)
+    }
+
+    fn error(payload: &str) -> String {
+        match decode_payload(payload) {
+            Err(InferenceError::InvalidImage(message)) => message,
+            other => panic!("expected an invalid image, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_base64_and_data_urls() {
+        let image = decode_payload(&encode(PNG)).unwrap();
+        assert_eq!(image.data, PNG);
+        assert_eq!(image.mime, "image/png");
+
+        let url = format!("data:image/png;base64,{}", encode(PNG));
+        assert_eq!(decode_payload(&url).unwrap().data, PNG);
+
+        // Line-wrapped base64
+        let encoded = encode(PNG);
+        let (start, end) = encoded.split_at(8);
+        let wrapped = format!("{}\r\n{}\n", start, end);
+        assert_eq!(decode_payload(&wrapped).unwrap().data, PNG);
+    }
+
+    #[test]
+    fn sniffs_the_type_of_the_image() {
+        // The declared media type is ignored
+        let url = format!("data:image/jpeg;base64,{}", encode(PNG));
+        assert_eq!(decode_payload(&url).unwrap().mime, "image/png");
+        let gif = format!("data:image/png;base64,{}", encode(b"GIF89a\x01\0\x01\0"));
+        assert_eq!(decode_payload(&gif).unwrap().mime, "image/gif");
+        let text = format!("data:image/png;base64,{}", encode(b"not an image"));
+        assert_eq!(error(&text), "unrecognized image format");
+    }
+
+    #[test]
+    fn rejects_invalid_payloads() {
+        assert!(error("not base64!").starts_with("invalid base64"));
+        assert!(error("data:image/png;base64,%%%").starts_with("invalid base64"));
+        assert_eq!(error("data:image/png;base64"), "data URL has no data");
+        let url = format!("data:image/png,{}", encode(PNG));
+        assert_eq!(error(&url), "data URLs must be base64 encoded");
+    }
+
+    #[test]
+    fn takes_one_image_or_an_array() {
+        let request: ImageRequest = serde_json::from_str(r#"{"image": "a", "top_k": 3}"#).unwrap();
+        assert!(matches!(request.image, ImagePayloads::One(ref image) if image == "a"));
+        assert_eq!(request.options.top_k, Some(3));
+
+        let request: ImageRequest = serde_json::from_str(r#"{"images": ["a", "b"]}"#).unwrap();
+        match request.image {
+            ImagePayloads::Many(images) => assert_eq!(images, ["a", "b"]),
+            other => panic!("expected several images, got {:?}", other),
+        }
+    }
+}
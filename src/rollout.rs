@@ -0,0 +1,374 @@
+// Canary and A/B rollouts of a candidate model. The requests that do not pick a model are split
+// between the primary model and the candidate by a stable hash of the client, or pinned to either
+// by API key or header, and the results of each variant are counted so that they can be compared.
+// In shadow mode the candidate also classifies the images of its share of the clients, or a sample
+// of them, but only the results of the primary are returned.
+use crate::config::{Rollout, Variant, CONFIG};
+use crate::inference::{self, content_hash, Classification, InferenceError, Verdict, REGISTRY};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Number of buckets the clients are hashed into, so that percentages have two decimals
+const BUCKETS: u64 = 10_000;
+
+// Define lazy-static variables to store the variants of the configured rollout, if any, the
+// metrics of each variant since startup, and the shadow runs owed to the candidate
+lazy_static! {
+    pub static ref ROLLOUT: Option<Variants> = Variants::from_config();
+    static ref METRICS: Mutex<[Counters; 2]> = Mutex::new(Default::default());
+    static ref SHADOW_CREDIT: Mutex<f32> = Mutex::new(0.);
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        inference::parse_option(s)
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variant::Primary => write!(f, "primary"),
+            Variant::Candidate => write!(f, "candidate"),
+        }
+    }
+}
+
+// The models or ensembles of the rollout, by variant.
+#[derive(Debug)]
+pub struct Variants {
+    pub primary: String,
+    pub candidate: String,
+}
+
+impl Variants {
+    // Check the configured rollout. Like an invalid registry, an invalid rollout aborts at
+    // startup.
+    fn from_config() -> Option<Variants> {
+        let config = &CONFIG.rollout;
+        let candidate = config.candidate.clone()?;
+        let primary = config
+            .primary
+            .clone()
+            .unwrap_or_else(|| REGISTRY.default_name().to_string());
+        for name in [&primary, &candidate] {
+            if REGISTRY.get(name).is_none() {
+                panic!("Rollout uses unknown model {}", name);
+            }
+        }
+        if primary == candidate {
+            panic!("Rollout uses model {} as both variants", primary);
+        }
+        if !(0. ..=100.).contains(&config.percent) {
+            panic!(
+                "Rollout percent {} is not between 0 and 100",
+                config.percent
+            );
+        }
+        if !(0. ..=1.).contains(&config.shadow_sample) {
+            panic!(
+                "Rollout shadow_sample {} is not between 0 and 1",
+                config.shadow_sample
+            );
+        }
+        let mode = if config.shadow {
+            format!(", in shadow mode for {} of them", config.shadow_sample)
+        } else {
+            String::new()
+        };
+        eprintln!(
+            "Rolling out {} against {} for {}% of the clients{}",
+            candidate, primary, config.percent, mode
+        );
+        Some(Variants { primary, candidate })
+    }
+
+    fn model(&self, variant: Variant) -> &str {
+        match variant {
+            Variant::Primary => &self.primary,
+            Variant::Candidate => &self.candidate,
+        }
+    }
+}
+
+// What a request says about the variant that should serve it.
+#[derive(Debug, Default, Clone)]
+pub struct Pinning {
+    // The X-API-Key header
+    pub api_key: Option<String>,
+    // The header configured to pin requests, naming a variant
+    pub variant: Option<String>,
+}
+
+// The variant that serves a request, and the model that classifies its image.
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub variant: Variant,
+    pub model: String,
+    // The candidate model, when it also classifies the image in the shadow of the primary
+    pub shadow: Option<String>,
+}
+
+// Assign a request to a variant of the rollout, unless it picks its model or no rollout is
+// configured. A pinning header takes precedence over a pinned API key, and other requests are
+// split by a hash of their API key, or else of their client address, or else of their image, so
+// that a client keeps being served by the same variant.
+pub fn assign(
+    model: Option<&str>,
+    pinning: &Pinning,
+    client: Option<SocketAddr>,
+    image: &[u8],
+) -> Result<Option<Assignment>, InferenceError> {
+    let Some(variants) = ROLLOUT.as_ref() else {
+        return Ok(None);
+    };
+    if model.is_some() {
+        return Ok(None);
+    }
+    let config = &CONFIG.rollout;
+    let variant = pinned_variant(pinning, config)?.unwrap_or_else(|| {
+        let sticky = match (&pinning.api_key, client) {
+            (Some(api_key), _) => api_key.as_bytes().to_vec(),
+            (None, Some(client)) => client.ip().to_string().into_bytes(),
+            (None, None) => image.to_vec(),
+        };
+        split(&sticky, config.percent)
+    });
+
+    // In shadow mode the primary serves every request, and the candidate only runs alongside it,
+    // for the sampled ones
+    Ok(Some(match (config.shadow, variant) {
+        (true, Variant::Candidate) => Assignment {
+            variant: Variant::Primary,
+            model: variants.primary.clone(),
+            shadow: sample_shadow(config.shadow_sample).then(|| variants.candidate.clone()),
+        },
+        (_, variant) => Assignment {
+            variant,
+            model: variants.model(variant).to_string(),
+            shadow: None,
+        },
+    }))
+}
+
+// The variant a request is pinned to by the rollout header, or else by its API key. The value of
+// an invalid header is not echoed back, since error pages quote the error.
+fn pinned_variant(pinning: &Pinning, config: &Rollout) -> Result<Option<Variant>, InferenceError> {
+    match &pinning.variant {
+        Some(variant) => variant.parse::<Variant>().map(Some).map_err(|_| {
+            InferenceError::InvalidInput(format!(
+                "invalid {} header: expected primary or candidate",
+                config.header
+            ))
+        }),
+        None => Ok(pinning
+            .api_key
+            .as_ref()
+            .and_then(|key| config.api_keys.get(key))
+            .copied()),
+    }
+}
+
+// The variant of a request that is not pinned: the candidate when the sticky key of its client
+// hashes into the given percentage of the buckets.
+fn split(sticky: &[u8], percent: f32) -> Variant {
+    let hash = content_hash(sticky);
+    let bucket = u64::from_str_radix(&hash[..16], 16).unwrap_or_default() % BUCKETS;
+    if (bucket as f32) < percent / 100. * BUCKETS as f32 {
+        Variant::Candidate
+    } else {
+        Variant::Primary
+    }
+}
+
+// Decide whether a request gets a shadow run. Every request adds the sampled fraction to the runs
+// owed to the candidate, and a run is made once a whole one is owed, so that the runs are evenly
+// spread over the requests.
+fn sample_shadow(fraction: f32) -> bool {
+    let mut credit = SHADOW_CREDIT.lock().unwrap();
+    *credit += fraction;
+    if *credit >= 1. {
+        *credit -= 1.;
+        true
+    } else {
+        false
+    }
+}
+
+// Running totals of the classifications of a variant.
+#[derive(Debug, Default)]
+struct Counters {
+    requests: u64,
+    errors: u64,
+    cached: u64,
+    latency_ms: f64,
+    confidence: f64,
+    unknown: u64,
+    // Shadow runs compared with the primary, and those with the same top label
+    compared: u64,
+    agreed: u64,
+}
+
+// Count a classification by a variant, or its failure. The result of a shadow run of the
+// candidate is compared with the top label of the primary.
+pub fn observe(
+    variant: Variant,
+    result: Result<(&Classification, bool), &InferenceError>,
+    latency: Duration,
+    primary_label: Option<&str>,
+) {
+    let mut metrics = METRICS.lock().unwrap();
+    let counters = &mut metrics[variant as usize];
+    counters.requests += 1;
+    let (classification, cached) = match result {
+        Ok(result) => result,
+        Err(_) => {
+            counters.errors += 1;
+            return;
+        }
+    };
+    counters.cached += cached as u64;
+    counters.latency_ms += latency.as_secs_f64() * 1000.;
+    let top = classification.predictions.first();
+    counters.confidence += top.map_or(0., |p| p.probability as f64);
+    if let Some(check) = &classification.open_set {
+        counters.unknown += (check.verdict == Verdict::Unknown) as u64;
+    }
+    if let Some(primary_label) = primary_label {
+        counters.compared += 1;
+        counters.agreed += top.is_some_and(|p| p.label == primary_label) as u64;
+    }
+}
+
+// The rollout and the metrics of its variants since startup.
+#[derive(Debug, Serialize)]
+pub struct RolloutReport {
+    pub primary: String,
+    pub candidate: String,
+    pub percent: f32,
+    pub shadow: bool,
+    pub variants: Vec<VariantReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariantReport {
+    pub variant: Variant,
+    pub model: String,
+    // Classifications requested, including those that failed and shadow runs
+    pub requests: u64,
+    pub errors: u64,
+    // Classifications served from the cache
+    pub cached: u64,
+    // Means over the classifications that did not fail. The confidence is the probability of the
+    // top class, which every model reports, so that variants with different outputs compare
+    pub mean_latency_ms: Option<f64>,
+    pub mean_confidence: Option<f64>,
+    // Classifications of images that the out-of-distribution check found unknown
+    pub unknown: u64,
+    // Fraction of the shadow runs whose top label is the top label of the primary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agreement: Option<f64>,
+}
+
+// Report on the configured rollout, if any.
+pub fn report() -> Option<RolloutReport> {
+    let variants = ROLLOUT.as_ref()?;
+    let metrics = METRICS.lock().unwrap();
+    let mean = |total: f64, count: u64| (count > 0).then(|| total / count as f64);
+    let reports = [Variant::Primary, Variant::Candidate]
+        .into_iter()
+        .map(|variant| {
+            let counters = &metrics[variant as usize];
+            let classified = counters.requests - counters.errors;
+            VariantReport {
+                variant,
+                model: variants.model(variant).to_string(),
+                requests: counters.requests,
+                errors: counters.errors,
+                cached: counters.cached,
+                mean_latency_ms: mean(counters.latency_ms, classified),
+                mean_confidence: mean(counters.confidence, classified),
+                unknown: counters.unknown,
+                agreement: mean(counters.agreed as f64, counters.compared),
+            }
+        })
+        .collect();
+    Some(RolloutReport {
+        primary: variants.primary.clone(),
+        candidate: variants.candidate.clone(),
+        percent: CONFIG.rollout.percent,
+        shadow: CONFIG.rollout.shadow,
+        variants: reports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_clients_by_percentage() {
+        for percent in [0., 5., 30., 100.] {
+            let clients = 10_000;
+            let candidates = (0..clients)
+                .map(|c| format!("10.{}.{}.{}", c / 65536, c / 256 % 256, c % 256))
+                .filter(|ip| split(ip.as_bytes(), percent) == Variant::Candidate)
+                .count();
+            let share = candidates as f32 / clients as f32 * 100.;
+            assert!((share - percent).abs() < 1.5, "{}% for {}%", share, percent);
+        }
+        // A client keeps its variant
+        let ip = b"192.168.1.20";
+        assert_eq!(split(ip, 30.), split(ip, 30.));
+    }
+
+    #[test]
+    fn pins_requests_by_header_or_api_key() {
+        let config = Rollout {
+            api_keys: [("tester".to_string(), Variant::Candidate)].into(),
+            ..Rollout::default()
+        };
+        let pinning = |variant: Option<&str>, api_key: Option<&str>| Pinning {
+            variant: variant.map(str::to_string),
+            api_key: api_key.map(str::to_string),
+        };
+        let pinned = |pinning: Pinning| pinned_variant(&pinning, &config).unwrap();
+        assert_eq!(pinned(pinning(None, None)), None);
+        assert_eq!(pinned(pinning(None, Some("other"))), None);
+        assert_eq!(
+            pinned(pinning(None, Some("tester"))),
+            Some(Variant::Candidate)
+        );
+        // The header takes precedence over the API key
+        assert_eq!(
+            pinned(pinning(Some("primary"), Some("tester"))),
+            Some(Variant::Primary)
+        );
+        assert_eq!(
+            pinned(pinning(Some("candidate"), None)),
+            Some(Variant::Candidate)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_variants_without_echoing_them() {
+        let pinning = Pinning {
+            variant: Some("<script>alert(1)</script>".to_string()),
+            api_key: None,
+        };
+        match pinned_variant(&pinning, &Rollout::default()) {
+            Err(InferenceError::InvalidInput(message)) => {
+                assert!(message.contains("x-model-variant"));
+                assert!(!message.contains("script"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,48 +1,81 @@
+use crate::assets;
+use crate::cache;
+use crate::comparison::{self, Comparison, ModelResult};
+use crate::config::{Variant, CONFIG};
+use crate::feedback::{self, Feedback, FeedbackError};
+use crate::history::{self, HistoryError, HistoryQuery};
+use crate::images::{self, Rendition};
+use crate::inference::explain::{self, ExplainOptions, Explanation};
+use crate::inference::{
+    self, Classification, InferenceError, InferenceOptions, Prediction, REGISTRY,
+};
+use crate::locale::{self, Locale};
+use crate::oip;
+use crate::payload::{self, ImagePayloads, ImageRequest};
+use crate::rollout::{self, Pinning};
+use crate::templates::render_template_context;
+use crate::tensor::{self, TensorFormat, TensorOptions};
+use base64::Engine;
 use futures_util::TryStreamExt;
-use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
 use std::fs;
-use tera::{Context, Tera};
-use warp::{Buf, Filter, Reply};
-
-// Define static variables for HTML templates
-static BASE_TEMPLATE: &str = include_str!("templates/base.html");
-static INDEX_TEMPLATE: &str = include_str!("templates/index.html");
-static INFERENCE_TEMPLATE: &str = include_str!("templates/inference.html");
-static NOT_FOUND_TEMPLATE: &str = include_str!("templates/404.html");
-
-// Define a lazy-static variable to store the Tera instance
-lazy_static! {
-    static ref TERA: Tera = {
-        let mut tera = Tera::default();
-
-        // Add the templates to the Tera instance
-        tera.add_raw_template("base.html", BASE_TEMPLATE).unwrap();
-        tera.add_raw_template("index.html", INDEX_TEMPLATE).unwrap();
-        tera.add_raw_template("inference.html", INFERENCE_TEMPLATE)
-            .unwrap();
-        tera.add_raw_template("404.html", NOT_FOUND_TEMPLATE)
-            .unwrap();
-        tera
-    };
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tera::Context;
+use tokio::sync::mpsc;
+use warp::http::StatusCode;
+use warp::multipart::FormData;
+use warp::{Buf, Filter, Rejection, Reply};
+
+// The lang query parameter, which overrides the Accept-Language header.
+#[derive(Debug, Default, Deserialize)]
+struct LangQuery {
+    lang: Option<String>,
 }
 
-fn render_template(template_name: &str) -> Result<String, String> {
-    let context = Context::new();
-    render_template_context(template_name, &context)
+// Pick the locale of a request from its lang parameter and its Accept-Language header.
+fn locale() -> impl Filter<Extract = (&'static Locale,), Error = Rejection> + Clone {
+    warp::query::<LangQuery>()
+        .or(warp::any().map(LangQuery::default))
+        .unify()
+        .and(warp::header::optional::<String>("accept-language"))
+        .map(|query: LangQuery, accept_language: Option<String>| {
+            locale::negotiate(query.lang.as_deref(), accept_language.as_deref())
+        })
 }
 
-fn render_template_context(template_name: &str, context: &tera::Context) -> Result<String, String> {
-    match TERA.render(template_name, &context) {
-        Ok(rendered) => Ok(rendered),
-        Err(e) => Err(format!("Error rendering template: {}", e)),
-    }
+// The headers pinning a request to a variant of the rollout.
+fn pinning() -> impl Filter<Extract = (Pinning,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("x-api-key")
+        .and(warp::header::optional::<String>(&CONFIG.rollout.header))
+        .map(|api_key, variant| Pinning { api_key, variant })
+}
+
+// Let the request through only when it carries the administration token as a bearer token. The
+// routes behind it are hidden as not found from every other request, and when no token is set.
+fn admin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|authorization: Option<String>| async move {
+            let token = CONFIG.admin.token.as_deref().filter(|t| !t.is_empty());
+            let sent = authorization
+                .as_deref()
+                .and_then(|a| a.strip_prefix("Bearer "));
+            match (token, sent) {
+                (Some(token), Some(sent)) if inference::same_token(token, sent) => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
 }
 
 pub fn root() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     warp::path::end()
-        .map(|| {
+        .and(locale())
+        .map(|locale: &'static Locale| {
             // Render the index template
-            let response = match render_template("index.html") {
+            let response = match render_template_context("index.html", &locale.context()) {
                 Ok(index_template) => {
                     // Return an HTML response with the rendered template
                     warp::reply::with_status(
@@ -53,10 +86,7 @@ pub fn root() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Cl
                 Err(err) => {
                     // Return an error HTML response with the template rendering error
                     warp::reply::with_status(
-                        warp::reply::html(format!(
-                            "<h1>Error rendering index template: {}</h1>",
-                            err
-                        )),
+                        html_error("Error rendering index template", &err),
                         warp::http::StatusCode::INTERNAL_SERVER_ERROR,
                     )
                 }
@@ -67,45 +97,134 @@ pub fn root() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Cl
         .boxed()
 }
 
-const UPLOADED_IMAGE_NAME: &str = "uploaded_image.jpg";
-
 pub fn inference() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     warp::path!("inference")
         .and(warp::post())
+        .and(warp::query::<InferenceOptions>())
+        .and(warp::query::<ExplainOptions>())
+        .and(warp::body::content_length_limit(
+            CONFIG.limits.max_body_bytes,
+        ))
+        .and(warp::body::bytes())
+        .and(warp::addr::remote())
+        .and(pinning())
+        .and(locale())
+        .map(
+            |options: InferenceOptions,
+             explain: ExplainOptions,
+             body: warp::hyper::body::Bytes,
+             client,
+             pinning: Pinning,
+             locale: &'static Locale| {
+                result_page(body, &options, &explain, client, &pinning, locale)
+            },
+        )
+        .boxed()
+}
+
+pub fn api_inference() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "inference")
+        .and(warp::post())
+        .and(warp::query::<InferenceOptions>())
+        .and(warp::query::<ExplainOptions>())
+        .and(warp::body::content_length_limit(
+            CONFIG.limits.max_body_bytes,
+        ))
         .and(warp::body::bytes())
-        .map(|body: warp::hyper::body::Bytes| {
-            // Process the raw image data here
-            let response = match process_image(body) {
-                Ok(results) => {
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(pinning())
+        .and(locale())
+        .map(
+            |options: InferenceOptions,
+             explain: ExplainOptions,
+             body: warp::hyper::body::Bytes,
+             client,
+             content_type: Option<String>,
+             pinning: Pinning,
+             locale: &'static Locale| {
+                // A JSON body holds base64 images along with their options
+                if content_type.is_some_and(|t| t.starts_with("application/json")) {
+                    return json_inference(&body, client, &pinning, locale);
+                }
+                let image_id = history::image_id(&body);
+                let mime = inference::mime_type(&body);
+                let image = body.clone();
+                let result = process_image(body, &options, &explain, client, &pinning).and_then(
+                    |processed| {
+                        let explanation = explain_image(
+                            &image,
+                            &processed.result_id,
+                            &processed.classification.model,
+                            &explain,
+                        )?;
+                        Ok((processed, explanation))
+                    },
+                );
+                // Return the results, or the inference error, as JSON
+                match result {
+                    Ok((processed, explanation)) => {
+                        let Processed {
+                            mut classification,
+                            cached,
+                            result_id,
+                        } = processed;
+                        locale.localize(&mut classification);
+                        warp::reply::json(&ClassificationResponse {
+                            image_id,
+                            feedback_token: feedback::token(&image),
+                            result_id,
+                            mime,
+                            cached,
+                            lang: &locale.lang,
+                            explanation,
+                            classification,
+                        })
+                        .into_response()
+                    }
+                    Err(err) => json_error(&err.to_string(), error_status(&err)).into_response(),
+                }
+            },
+        )
+        .boxed()
+}
+
+// Browse the history. Records hold the addresses of the clients, so it is an administration route.
+pub fn history() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("history")
+        .and(warp::get())
+        .and(admin())
+        .and(warp::query::<HistoryQuery>())
+        .map(|query: HistoryQuery| {
+            // Render the matching page of the history
+            let response = match history::query(&query) {
+                Ok(page) => {
                     let mut context = Context::new();
-                    context.insert("path_to_image", UPLOADED_IMAGE_NAME);
-                    context.insert("inference_result", &results);
+                    context.insert("query", &query);
+                    context.insert("history", &page);
 
-                    match render_template_context("inference.html", &context) {
-                        Ok(inference_template) => {
+                    match render_template_context("history.html", &context) {
+                        Ok(history_template) => {
                             // Return an HTML response with the rendered template
                             warp::reply::with_status(
-                                warp::reply::html(inference_template),
+                                warp::reply::html(history_template),
                                 warp::http::StatusCode::OK,
                             )
                         }
                         Err(err) => {
                             // Return an error HTML response with the template rendering error
                             warp::reply::with_status(
-                                warp::reply::html(format!(
-                                    "<h1>Error rendering inference template: {}</h1>",
-                                    err
-                                )),
+                                html_error("Error rendering history template", &err),
                                 warp::http::StatusCode::INTERNAL_SERVER_ERROR,
                             )
                         }
                     }
                 }
                 Err(err) => {
-                    // Return an error HTML response with the inference error
+                    // Return an error HTML response with the history error
                     warp::reply::with_status(
-                        warp::reply::html(format!("<h1>Error processing image: {}</h1>", err)),
-                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        html_error("Error reading history", &err),
+                        history_error_status(&err),
                     )
                 }
             };
@@ -115,144 +234,524 @@ pub fn inference() -> impl Filter<Extract = impl Reply, Error = warp::Rejection>
         .boxed()
 }
 
-pub fn upload() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
-    warp::path!("upload")
+// Query the history as JSON, an administration route like the history page.
+pub fn api_history() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "history")
+        .and(warp::get())
+        .and(admin())
+        .and(warp::query::<HistoryQuery>())
+        .map(|query: HistoryQuery| {
+            // Return the matching page of the history, or the history error, as JSON
+            match history::query(&query) {
+                Ok(page) => warp::reply::with_status(warp::reply::json(&page), StatusCode::OK),
+                Err(err) => json_error(&err.to_string(), history_error_status(&err)),
+            }
+        })
+        .boxed()
+}
+
+// Serve the copies of an uploaded image, as "<image ID>/thumbnail.png",
+// "<image ID>/annotated-<result ID>.png" and "<image ID>/explanation-<result ID>.png".
+pub fn image_rendition() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("images" / String / String)
+        .and(warp::get())
+        .and_then(|image_id: String, name: String| async move {
+            let path = Rendition::from_file_name(&name)
+                .and_then(|rendition| images::path(&image_id, &rendition))
+                .ok_or_else(warp::reject::not_found)?;
+            let data = fs::read(path).map_err(|_| warp::reject::not_found())?;
+            Ok::<_, Rejection>(warp::reply::with_header(data, "Content-Type", "image/png"))
+        })
+        .boxed()
+}
+
+pub fn feedback() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("feedback")
         .and(warp::post())
-        .and(warp::multipart::form().max_length(5 * 1024 * 1024)) // Set a 5MB limit on the multipart form data
-        .and_then(|form: warp::multipart::FormData| {
-            async {
-                // Iterate over the form fields
-                let result: Result<Vec<_>, _> = form
-                    .and_then(|part| async {
-                        // Check if the field is a file
-                        if let Some(filename) = part.filename() {
-                            // Check if the file is a JPEG image
-                            //if filename.ends_with(".jpg") || filename.ends_with(".jpeg") {
-                            //    // Process the raw image data here
-                            //    match field.data().await {
-                            //        Some(Ok(buf)) => {
-                            //            match process_image(buf.copy_to_bytes(buf.remaining())) {
-                            //                Ok(results) => {
-                            //                    let mut context = Context::new();
-                            //                    context.insert("path_to_image", UPLOADED_IMAGE_NAME);
-                            //                    context.insert("inference_result", &results);
-
-                            //                    match render_template_context("inference.html", &context) {
-                            //                        Ok(inference_template) => {
-                            //                            // Return an HTML response with the rendered template
-                            //                            Ok(warp::reply::with_status(
-                            //                                warp::reply::html(inference_template),
-                            //                                warp::http::StatusCode::OK,
-                            //                            ))
-                            //                        }
-                            //                        Err(err) => {
-                            //                            // Return an error HTML response with the template rendering error
-                            //                            Ok(warp::reply::with_status(
-                            //                                warp::reply::html(format!(
-                            //                                    "<h1>Error rendering inference template: {}</h1>",
-                            //                                    err
-                            //                                )),
-                            //                                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                            //                            ))
-                            //                        }
-                            //                    }
-                            //                }
-                            //                Err(err) => {
-                            //                    // Return an error HTML response with the inference error
-                            //                    Ok(warp::reply::with_status(
-                            //                        warp::reply::html(format!(
-                            //                            "<h1>Error processing image: {}</h1>",
-                            //                            err
-                            //                        )),
-                            //                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                            //                    ))
-                            //                }
-                            //            }
-                            //        }
-                            //        Some(Err(err)) => {
-                            //            // Return an error if the field data could not be read
-                            //            Ok(warp::reply::with_status(
-                            //                warp::reply::html(format!(
-                            //                    "<h1>Error reading field data: {}</h1>",
-                            //                    err
-                            //                )),
-                            //                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                            //            ))
-                            //            //return Err(warp::reject::custom(UploadError::ReadError(
-                            //            //    err.to_string(),
-                            //            //)));
-                            //        }
-                            //        //Some(Ok(buf)) => Some(Ok(warp::hyper::body::Bytes::from(
-                            //        //    buf.copy_to_bytes(buf.remaining()).to_vec(),
-                            //        //))),
-                            //        //Some(Err(err)) => {
-                            //        //    // Return an error if the field data could not be read
-                            //        //    Some(Err(warp::reject::custom(UploadError::ReadError(
-                            //        //        err.to_string(),
-                            //        //    ))))
-                            //        //}
-                            //    }
-                            //    // Return the field if it is a JPEG image
-                            //} else {
-                            //    // Return an error if the file is not a JPEG image
-                            //    Ok(warp::reply::with_status(
-                            //        warp::reply::html(format!("<h1>Not a JPEG image</h1>")),
-                            //        warp::http::StatusCode::BAD_REQUEST,
-                            //    ))
-                            //    //return Err(warp::reject::custom(UploadError::NotAJpegImage));
-                            //}
-                            Ok(warp::reply::with_status(
-                                warp::reply::html(format!("<h1>A file!</h1>")),
+        .and(warp::body::content_length_limit(
+            CONFIG.limits.max_body_bytes,
+        ))
+        .and(warp::body::form::<Feedback>())
+        .map(|feedback: Feedback| {
+            // Store the feedback sent from the result page
+            let response = match feedback::submit(&feedback) {
+                Ok(record) => {
+                    let mut context = Context::new();
+                    context.insert("feedback", &record);
+
+                    match render_template_context("feedback.html", &context) {
+                        Ok(feedback_template) => {
+                            // Return an HTML response with the rendered template
+                            warp::reply::with_status(
+                                warp::reply::html(feedback_template),
                                 warp::http::StatusCode::OK,
-                            ))
-                        } else {
-                            // Return an error if the field is not a file
-                            Ok(warp::reply::with_status(
-                                warp::reply::html(format!("<h1>Not a file</h1>")),
-                                warp::http::StatusCode::BAD_REQUEST,
-                            ))
-                            //return Err(warp::reject::custom(UploadError::NotAFile));
+                            )
+                        }
+                        Err(err) => {
+                            // Return an error HTML response with the template rendering error
+                            warp::reply::with_status(
+                                html_error("Error rendering feedback template", &err),
+                                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                            )
                         }
+                    }
+                }
+                Err(err) => {
+                    // Return an error HTML response with the feedback error
+                    warp::reply::with_status(
+                        html_error("Error saving feedback", &err),
+                        feedback_error_status(&err),
+                    )
+                }
+            };
+            // Return the HTML response
+            response
+        })
+        .boxed()
+}
+
+pub fn api_feedback() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "feedback")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            CONFIG.limits.max_body_bytes,
+        ))
+        .and(warp::body::json::<Feedback>())
+        .map(|feedback: Feedback| {
+            // Return the stored feedback, or the feedback error, as JSON
+            match feedback::submit(&feedback) {
+                Ok(record) => warp::reply::with_status(warp::reply::json(&record), StatusCode::OK),
+                Err(err) => json_error(&err.to_string(), feedback_error_status(&err)),
+            }
+        })
+        .boxed()
+}
+
+// Download the images with feedback as an ImageFolder-style dataset in a tar archive.
+pub fn api_feedback_export() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "feedback" / "export")
+        .and(warp::get())
+        .and(admin())
+        .map(|| match feedback::export() {
+            Ok(archive) => warp::reply::with_header(
+                warp::reply::with_header(archive, "Content-Type", "application/x-tar"),
+                "Content-Disposition",
+                "attachment; filename=\"feedback-dataset.tar\"",
+            )
+            .into_response(),
+            Err(err) => json_error(&err.to_string(), feedback_error_status(&err)).into_response(),
+        })
+        .boxed()
+}
+
+// Download the copy of an uploaded image annotated with one of its results.
+pub fn api_image_annotated() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "images" / String / "annotated" / String)
+        .and(warp::get())
+        .map(|image_id: String, result_id: String| {
+            let data = images::path(&image_id, &Rendition::Annotated(result_id.clone()))
+                .and_then(|p| fs::read(p).ok());
+            match data {
+                Some(data) => warp::reply::with_header(
+                    warp::reply::with_header(data, "Content-Type", "image/png"),
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"{}-{}-annotated.png\"",
+                        image_id, result_id
+                    ),
+                )
+                .into_response(),
+                None => json_error(
+                    &format!("no annotated image: {} {}", image_id, result_id),
+                    StatusCode::NOT_FOUND,
+                )
+                .into_response(),
+            }
+        })
+        .boxed()
+}
+
+// Drop every cached classification result.
+pub fn api_admin_cache() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "admin" / "cache")
+        .and(warp::delete())
+        .and(admin())
+        .map(|| match cache::clear() {
+            Ok(cleared) => warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "cleared": cleared })),
+                StatusCode::OK,
+            ),
+            Err(err) => json_error(
+                &format!("failed to clear the cache: {}", err),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        })
+        .boxed()
+}
+
+// Report on the rollout, with the metrics of its variants since startup.
+pub fn api_admin_rollout() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "admin" / "rollout")
+        .and(warp::get())
+        .and(admin())
+        .map(|| match rollout::report() {
+            Some(report) => warp::reply::with_status(warp::reply::json(&report), StatusCode::OK),
+            None => json_error("no rollout is configured", StatusCode::NOT_FOUND),
+        })
+        .boxed()
+}
+
+// Run a preprocessed tensor through a model as-is and return the raw output tensor, encoded like
+// the input unless the format parameter says otherwise.
+pub fn api_tensor() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "tensor")
+        .and(warp::post())
+        .and(warp::query::<TensorOptions>())
+        .and(warp::body::content_length_limit(
+            CONFIG.limits.max_body_bytes,
+        ))
+        .and(warp::body::bytes())
+        .and(warp::header::optional::<String>("content-type"))
+        .map(
+            |options: TensorOptions,
+             body: warp::hyper::body::Bytes,
+             content_type: Option<String>| {
+                let result = tensor::decode(&body, content_type.as_deref(), &options).and_then(
+                    |(input, format)| {
+                        let (model, output) = tensor::run(options.model.as_deref(), &input)?;
+                        Ok((model, output, options.format.unwrap_or(format)))
+                    },
+                );
+                match result {
+                    Ok((model, output, TensorFormat::Json)) => {
+                        warp::reply::json(&tensor::encode_json(model, &output)).into_response()
+                    }
+                    Ok((_, output, TensorFormat::Binary)) => warp::reply::with_header(
+                        warp::reply::with_header(
+                            warp::reply::with_header(
+                                tensor::encode_binary(&output),
+                                "Content-Type",
+                                "application/octet-stream",
+                            ),
+                            "X-Tensor-Shape",
+                            tensor::shape_header(&output),
+                        ),
+                        "X-Tensor-Dtype",
+                        tensor::DTYPE,
+                    )
+                    .into_response(),
+                    Ok((_, output, TensorFormat::Npy)) => warp::reply::with_header(
+                        tensor::encode_npy(&output),
+                        "Content-Type",
+                        "application/x-npy",
+                    )
+                    .into_response(),
+                    Err(err) => json_error(&err.to_string(), error_status(&err)).into_response(),
+                }
+            },
+        )
+        .boxed()
+}
+
+// Open Inference Protocol: server metadata.
+pub fn oip_server() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2")
+        .and(warp::get())
+        .map(|| warp::reply::json(&oip::server_metadata()))
+        .boxed()
+}
+
+// Open Inference Protocol: the server is live as soon as it answers.
+pub fn oip_health_live() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "health" / "live")
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({ "live": true })))
+        .boxed()
+}
+
+// Open Inference Protocol: the models are loaded at startup, so a live server is also ready.
+pub fn oip_health_ready() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "health" / "ready")
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({ "ready": true })))
+        .boxed()
+}
+
+// Open Inference Protocol: the input and output tensors of a model.
+pub fn oip_model_metadata() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "models" / String)
+        .and(warp::get())
+        .map(|name: String| match oip::model(&name) {
+            Ok(model) => warp::reply::with_status(
+                warp::reply::json(&oip::model_metadata(model)),
+                StatusCode::OK,
+            ),
+            Err(err) => json_error(&err.to_string(), error_status(&err)),
+        })
+        .boxed()
+}
+
+// Open Inference Protocol: whether a model is ready, which every model in the registry is.
+pub fn oip_model_ready() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "models" / String / "ready")
+        .and(warp::get())
+        .map(|name: String| match oip::model(&name) {
+            Ok(model) => warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "name": model.name, "ready": true })),
+                StatusCode::OK,
+            ),
+            Err(err) => json_error(&err.to_string(), error_status(&err)),
+        })
+        .boxed()
+}
+
+// Open Inference Protocol: run a model on JSON tensors, JSON tensors followed by binary data
+// (binary tensor data extension), or a bare image. Outputs are returned the same way.
+pub fn oip_infer() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "models" / String / "infer")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            CONFIG.limits.max_body_bytes,
+        ))
+        .and(warp::body::bytes())
+        .and(warp::header::optional::<usize>(
+            "inference-header-content-length",
+        ))
+        .and(warp::header::optional::<String>("content-type"))
+        .map(
+            |name: String,
+             body: warp::hyper::body::Bytes,
+             header_length: Option<usize>,
+             content_type: Option<String>| {
+                let response = oip::model(&name)
+                    .and_then(|model| {
+                        oip::infer(model, &body, header_length, content_type.as_deref())
                     })
-                    .try_collect()
-                    .await
-                    .map_err(|e| {
-                        // Return an error HTML response with the form processing error
-                        warp::reply::with_status(
-                            warp::reply::html(format!(
-                                "<h1>Error processing form data: {}</h1>",
-                                e
-                            )),
-                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        )
-                    });
-                // Return the HTML response
-                //warp::reply::with_status(result.unwrap_or_else(|e| {
-                //    // Return an error HTML response with the form processing error
-                //    warp::reply::with_status(
-                //        warp::reply::html(format!("<h1>Error processing form data</h1>")),
-                //        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                //    )
-                //}))
-                //result.unwrap_or_else(|e| {
-                //    // Return an error HTML response with the form processing error
-                //    let mut v = Vec::new();
-                //    v.push(warp::reply::with_status(
-                //        warp::reply::html(format!("<h1>Error processing form data</h1>")),
-                //        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                //    ));
-                //    v
-                //})
-                result
+                    .and_then(|response| response.encode());
+                // Return the outputs as JSON, followed by those sent as binary data, or return the
+                // inference error as JSON
+                match response {
+                    Ok((body, None)) => {
+                        warp::reply::with_header(body, "Content-Type", "application/json")
+                            .into_response()
+                    }
+                    Ok((body, Some(header_length))) => warp::reply::with_header(
+                        warp::reply::with_header(body, "Content-Type", "application/octet-stream"),
+                        "Inference-Header-Content-Length",
+                        header_length,
+                    )
+                    .into_response(),
+                    Err(err) => json_error(&err.to_string(), error_status(&err)).into_response(),
+                }
+            },
+        )
+        .boxed()
+}
+
+// Classify the image uploaded with the forms of the index and result pages.
+pub fn upload() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("upload")
+        .and(warp::post())
+        .and(warp::query::<InferenceOptions>())
+        .and(warp::query::<ExplainOptions>())
+        .and(warp::multipart::form().max_length(CONFIG.limits.max_body_bytes))
+        .and(warp::addr::remote())
+        .and(pinning())
+        .and(locale())
+        .and_then(
+            |options: InferenceOptions,
+             explain: ExplainOptions,
+             form: FormData,
+             client,
+             pinning: Pinning,
+             locale: &'static Locale| async move {
+                let response = match read_upload(form).await {
+                    Ok(Upload {
+                        file: Some(image), ..
+                    }) => result_page(image.into(), &options, &explain, client, &pinning, locale),
+                    Ok(_) => warp::reply::with_status(
+                        warp::reply::html(
+                            "<h1>Error processing form data: no file was uploaded</h1>".to_string(),
+                        ),
+                        StatusCode::BAD_REQUEST,
+                    ),
+                    Err(err) => warp::reply::with_status(
+                        html_error("Error processing form data", &err),
+                        StatusCode::BAD_REQUEST,
+                    ),
+                };
+                Ok::<_, Rejection>(response)
+            },
+        )
+        .boxed()
+}
+
+// The contents of an upload form: the first part that holds a file, and the other fields by name.
+#[derive(Default)]
+struct Upload {
+    file: Option<Vec<u8>>,
+    fields: Vec<(String, String)>,
+}
+
+// Read an upload form. Browsers send an empty file name when no file was chosen.
+async fn read_upload(mut form: FormData) -> Result<Upload, warp::Error> {
+    let mut upload = Upload::default();
+    while let Some(part) = form.try_next().await? {
+        let name = part.name().to_string();
+        let filename = part.filename().map(str::to_string);
+        let data = part
+            .stream()
+            .try_fold(Vec::new(), |mut data, buf| async move {
+                data.extend_from_slice(buf.chunk());
+                Ok(data)
+            })
+            .await?;
+        match filename {
+            None => upload
+                .fields
+                .push((name, String::from_utf8_lossy(&data).into_owned())),
+            Some(filename) if !filename.is_empty() && upload.file.is_none() => {
+                upload.file = Some(data)
             }
+            Some(_) => {}
+        }
+    }
+    Ok(upload)
+}
+
+// The models query parameter of the comparison API, a comma-separated list of models and
+// ensembles. Every model that is served is compared when it is missing.
+#[derive(Debug, Default, Deserialize)]
+struct CompareQuery {
+    models: Option<String>,
+}
+
+// Show the form of the comparison page.
+pub fn compare_form() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("compare")
+        .and(warp::get())
+        .and(locale())
+        .map(|locale: &'static Locale| compare_page(None, locale))
+        .boxed()
+}
+
+// Compare the models checked in the form of the comparison page on the uploaded image, or every
+// model when none is checked.
+pub fn compare() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("compare")
+        .and(warp::post())
+        .and(warp::query::<InferenceOptions>())
+        .and(warp::multipart::form().max_length(CONFIG.limits.max_body_bytes))
+        .and(warp::addr::remote())
+        .and(locale())
+        .and_then(
+            |options: InferenceOptions, form: FormData, client, locale: &'static Locale| async move {
+                let upload = match read_upload(form).await {
+                    Ok(upload) => upload,
+                    Err(err) => {
+                        return Ok::<_, Rejection>(warp::reply::with_status(
+                            html_error("Error processing form data", &err),
+                            StatusCode::BAD_REQUEST,
+                        ))
+                    }
+                };
+                let Some(image) = upload.file else {
+                    return Ok(warp::reply::with_status(
+                        warp::reply::html(
+                            "<h1>Error processing form data: no file was uploaded</h1>".to_string(),
+                        ),
+                        StatusCode::BAD_REQUEST,
+                    ));
+                };
+                let checked: Vec<&str> = upload
+                    .fields
+                    .iter()
+                    .filter(|(name, _)| name == "model")
+                    .map(|(_, value)| value.as_str())
+                    .collect();
+                let list = (!checked.is_empty()).then(|| checked.join(","));
+                let image: warp::hyper::body::Bytes = image.into();
+                let image_id = history::image_id(&image);
+                let result = comparison::model_names(list.as_deref())
+                    .and_then(|names| compare_image(image, &names, &options, client, locale));
+                Ok(match result {
+                    Ok(comparison) => compare_page(Some((image_id, comparison)), locale),
+                    Err(err) => warp::reply::with_status(
+                        html_error("Error processing image", &err),
+                        error_status(&err),
+                    ),
+                })
+            },
+        )
+        .boxed()
+}
+
+// Compare models on an image posted as the request body, returning the results as JSON.
+pub fn api_compare() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "v1" / "compare")
+        .and(warp::post())
+        .and(warp::query::<CompareQuery>())
+        .and(warp::query::<InferenceOptions>())
+        .and(warp::body::content_length_limit(
+            CONFIG.limits.max_body_bytes,
+        ))
+        .and(warp::body::bytes())
+        .and(warp::addr::remote())
+        .and(locale())
+        .map(
+            |query: CompareQuery,
+             options: InferenceOptions,
+             body: warp::hyper::body::Bytes,
+             client,
+             locale: &'static Locale| {
+                let image_id = history::image_id(&body);
+                let result = comparison::model_names(query.models.as_deref())
+                    .and_then(|names| compare_image(body, &names, &options, client, locale));
+                match result {
+                    Ok(comparison) => warp::reply::json(&ComparisonResponse {
+                        image_id,
+                        lang: &locale.lang,
+                        comparison: &comparison,
+                    })
+                    .into_response(),
+                    Err(err) => json_error(&err.to_string(), error_status(&err)).into_response(),
+                }
+            },
+        )
+        .boxed()
+}
+
+// Serve the stylesheet, scripts and icons of the pages, answering 304 when the ETag a browser
+// revalidates with is still current.
+pub fn static_asset() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::path!("static" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(|name: String, if_none_match: Option<String>| async move {
+            let asset = assets::get(&name).ok_or_else(warp::reject::not_found)?;
+            let builder = warp::http::Response::builder()
+                .header("ETag", &asset.etag)
+                .header("Cache-Control", &asset.cache_control);
+            let response = if if_none_match.is_some_and(|tags| asset.matches(&tags)) {
+                builder
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(warp::hyper::Body::empty())
+            } else {
+                builder
+                    .header("Content-Type", asset.content_type)
+                    .body(warp::hyper::Body::from(asset.data.into_owned()))
+            };
+            response.map_err(|_| warp::reject::not_found())
         })
+        .boxed()
 }
 
 pub fn not_found() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     warp::any()
-        .map(|| {
+        .and(locale())
+        .map(|locale: &'static Locale| {
             // Render the 404 template
-            let response = match render_template("404.html") {
+            let response = match render_template_context("404.html", &locale.context()) {
                 Ok(not_found_template) => {
                     // Return an HTML response with the rendered template
                     warp::reply::with_status(
@@ -263,10 +762,7 @@ pub fn not_found() -> impl Filter<Extract = impl Reply, Error = warp::Rejection>
                 Err(err) => {
                     // Return an error HTML response with the template rendering error
                     warp::reply::with_status(
-                        warp::reply::html(format!(
-                            "<h1>Error rendering 404 template: {}</h1>",
-                            err
-                        )),
+                        html_error("Error rendering 404 template", &err),
                         warp::http::StatusCode::INTERNAL_SERVER_ERROR,
                     )
                 }
@@ -277,13 +773,752 @@ pub fn not_found() -> impl Filter<Extract = impl Reply, Error = warp::Rejection>
         .boxed()
 }
 
-fn process_image(image_data: warp::hyper::body::Bytes) -> Result<String, String> {
-    // Save the image data to the file
-    if let Err(err) = fs::write(UPLOADED_IMAGE_NAME, image_data.as_ref()) {
-        Err(format!("Failed to save image: {}", err))
+// Turn the rejections of the request bodies and queries of the web pages into HTML error pages.
+// Any other rejection is passed on so that unknown routes still fall through to the 404 page.
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    let (status, message) = rejection_error(&err).ok_or(err)?;
+    Ok(warp::reply::with_status(
+        html_error("Error processing request", &message),
+        status,
+    ))
+}
+
+// Turn the same rejections of the API routes into JSON errors.
+pub async fn handle_api_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    let (status, message) = rejection_error(&err).ok_or(err)?;
+    Ok(json_error(&message, status))
+}
+
+// The status and message of a request whose body or query was rejected, e.g. one too large or
+// malformed, or None for a rejection that does not come from the request.
+fn rejection_error(err: &Rejection) -> Option<(StatusCode, String)> {
+    if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        Some((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Request body is larger than the limit of {} bytes",
+                CONFIG.limits.max_body_bytes
+            ),
+        ))
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        Some((
+            StatusCode::LENGTH_REQUIRED,
+            "Request body must declare a Content-Length".to_string(),
+        ))
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        Some((StatusCode::BAD_REQUEST, e.to_string()))
+    } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        Some((StatusCode::BAD_REQUEST, e.to_string()))
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        Some((StatusCode::BAD_REQUEST, e.to_string()))
     } else {
-        println!("Image saved to: {}", UPLOADED_IMAGE_NAME);
-        let results = crate::inference::infer_image(UPLOADED_IMAGE_NAME);
-        Ok(results)
+        None
+    }
+}
+
+// Classify an image posted from a web page, and render the result page or an error page.
+fn result_page(
+    body: warp::hyper::body::Bytes,
+    options: &InferenceOptions,
+    explain: &ExplainOptions,
+    client: Option<SocketAddr>,
+    pinning: &Pinning,
+    locale: &'static Locale,
+) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    let start = Instant::now();
+    let image_id = history::image_id(&body);
+    let mime = inference::mime_type(&body);
+    let image = body.clone();
+    let result = process_image(body, options, explain, client, pinning).and_then(|processed| {
+        let explanation = explain_image(
+            &image,
+            &processed.result_id,
+            &processed.classification.model,
+            explain,
+        )?;
+        Ok((processed, explanation))
+    });
+    let (
+        Processed {
+            mut classification,
+            cached,
+            result_id,
+        },
+        explanation,
+    ) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            // Return an error HTML response with the inference error
+            return warp::reply::with_status(
+                html_error("Error processing image", &err),
+                error_status(&err),
+            );
+        }
+    };
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.;
+    locale.localize(&mut classification);
+
+    // The page links to the same JSON as the API returns, as a data URL
+    let response = ClassificationResponse {
+        image_id,
+        feedback_token: feedback::token(&image),
+        result_id,
+        mime,
+        cached,
+        lang: &locale.lang,
+        explanation,
+        classification,
+    };
+    let json = serde_json::to_vec_pretty(&response).unwrap_or_default();
+    let ClassificationResponse {
+        image_id,
+        feedback_token,
+        result_id,
+        explanation,
+        classification,
+        ..
+    } = response;
+
+    let predictions = prediction_rows(&classification.predictions);
+    let probabilities: Vec<f32> = classification
+        .categories
+        .iter()
+        .map(|c| c.probability)
+        .collect();
+    let categories: Vec<ScoreRow> = classification
+        .categories
+        .iter()
+        .zip(bar_lengths(&probabilities))
+        .map(|(category, bar)| {
+            let label = category
+                .localized_label
+                .as_deref()
+                .unwrap_or(&category.category);
+            ScoreRow {
+                class_id: None,
+                label,
+                canonical: (label != category.category).then_some(category.category.as_str()),
+                probability: category.probability,
+                bar,
+            }
+        })
+        .collect();
+    let augmentations: Vec<String> = classification
+        .augmentations
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+
+    let mut context = locale.context();
+    context.insert("image_id", &image_id);
+    context.insert("feedback_token", &feedback_token);
+    context.insert("result_id", &result_id);
+    context.insert("cached", &cached);
+    context.insert("elapsed_ms", &elapsed_ms);
+    context.insert("classification", &classification);
+    context.insert("predictions", &predictions);
+    context.insert("categories", &categories);
+    context.insert("augmentations", &augmentations);
+    context.insert("open_set", &classification.open_set);
+    context.insert("explanation", &explanation);
+    if let Some(explanation) = &explanation {
+        let label = &explanation.explanation.label;
+        context.insert("explanation_label", locale.label(label).unwrap_or(label));
+    }
+    context.insert(
+        "json_url",
+        &format!(
+            "data:application/json;base64,{}",
+            base64::engine::general_purpose::STANDARD.encode(json)
+        ),
+    );
+    // Feedback can only be given on the images stored in the history
+    context.insert("feedback", &CONFIG.history.enabled);
+
+    match render_template_context("inference.html", &context) {
+        Ok(inference_template) => {
+            // Return an HTML response with the rendered template
+            warp::reply::with_status(
+                warp::reply::html(inference_template),
+                warp::http::StatusCode::OK,
+            )
+        }
+        Err(err) => {
+            // Return an error HTML response with the template rendering error
+            warp::reply::with_status(
+                html_error("Error rendering inference template", &err),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+// A row of a table of probabilities of the result page, with the length of its bar in percent.
+#[derive(Serialize)]
+struct ScoreRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class_id: Option<usize>,
+    label: &'a str,
+    // The canonical label, when the label shown is a translation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    canonical: Option<&'a str>,
+    probability: f32,
+    bar: f32,
+}
+
+// The rows of the table of probabilities of localized predictions.
+fn prediction_rows(predictions: &[Prediction]) -> Vec<ScoreRow<'_>> {
+    let probabilities: Vec<f32> = predictions.iter().map(|p| p.probability).collect();
+    predictions
+        .iter()
+        .zip(bar_lengths(&probabilities))
+        .map(|(prediction, bar)| {
+            let label = prediction
+                .localized_label
+                .as_deref()
+                .unwrap_or(&prediction.label);
+            ScoreRow {
+                class_id: Some(prediction.class_id),
+                label,
+                canonical: (label != prediction.label).then_some(prediction.label.as_str()),
+                probability: prediction.probability,
+                bar,
+            }
+        })
+        .collect()
+}
+
+// The lengths of the bars of a table of probabilities, in percent, which the probabilities fill in
+// proportion.
+fn bar_lengths(probabilities: &[f32]) -> Vec<f32> {
+    probabilities
+        .iter()
+        .map(|p| {
+            if p.is_nan() {
+                0.
+            } else {
+                (p * 100.).clamp(0., 100.)
+            }
+        })
+        .collect()
+}
+
+// Classify an image with each of the named models, timing each one, and compare the results.
+fn compare_image(
+    body: warp::hyper::body::Bytes,
+    names: &[String],
+    options: &InferenceOptions,
+    client: Option<SocketAddr>,
+    locale: &'static Locale,
+) -> Result<Comparison, InferenceError> {
+    let mut results = Vec::new();
+    for name in names {
+        let options = InferenceOptions {
+            model: Some(name.clone()),
+            ..options.clone()
+        };
+        let start = Instant::now();
+        let Processed {
+            mut classification,
+            cached,
+            ..
+        } = process_image(
+            body.clone(),
+            &options,
+            &ExplainOptions::default(),
+            client,
+            &Pinning::default(),
+        )?;
+        let elapsed_ms = start.elapsed().as_secs_f64() * 1000.;
+        locale.localize(&mut classification);
+        results.push(ModelResult {
+            elapsed_ms,
+            cached,
+            classification,
+        });
+    }
+    Ok(Comparison::new(results))
+}
+
+// Render the comparison page, with the results of comparing models on an image when there are
+// some. The models that were compared are checked in its form.
+fn compare_page(
+    compared: Option<(String, Comparison)>,
+    locale: &'static Locale,
+) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    let mut context = locale.context();
+    let checked = |name: &str| {
+        compared.as_ref().is_some_and(|(_, comparison)| {
+            comparison
+                .results
+                .iter()
+                .any(|r| r.classification.model == name)
+        })
+    };
+    let models: Vec<ModelChoice> = REGISTRY
+        .names()
+        .map(|name| ModelChoice {
+            name,
+            checked: checked(name),
+        })
+        .collect();
+    context.insert("models", &models);
+
+    if let Some((image_id, comparison)) = &compared {
+        let columns: Vec<ComparisonColumn> = comparison
+            .results
+            .iter()
+            .map(|result| ComparisonColumn {
+                result,
+                rows: prediction_rows(&result.classification.predictions),
+            })
+            .collect();
+        let json = serde_json::to_vec_pretty(&ComparisonResponse {
+            image_id: image_id.clone(),
+            lang: &locale.lang,
+            comparison,
+        })
+        .unwrap_or_default();
+        context.insert("image_id", image_id);
+        context.insert("columns", &columns);
+        context.insert("agreement", &comparison.agreement);
+        context.insert(
+            "json_url",
+            &format!(
+                "data:application/json;base64,{}",
+                base64::engine::general_purpose::STANDARD.encode(json)
+            ),
+        );
+    }
+
+    match render_template_context("compare.html", &context) {
+        Ok(compare_template) => {
+            warp::reply::with_status(warp::reply::html(compare_template), StatusCode::OK)
+        }
+        Err(err) => warp::reply::with_status(
+            html_error("Error rendering compare template", &err),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
+
+// A model or ensemble of the form of the comparison page.
+#[derive(Serialize)]
+struct ModelChoice<'a> {
+    name: &'a str,
+    checked: bool,
+}
+
+// The result of one model on the comparison page, with its score table.
+#[derive(Serialize)]
+struct ComparisonColumn<'a> {
+    result: &'a ModelResult,
+    rows: Vec<ScoreRow<'a>>,
+}
+
+// A comparison as returned by the API.
+#[derive(Serialize)]
+struct ComparisonResponse<'a> {
+    image_id: String,
+    // The language of the localized labels
+    lang: &'static str,
+    #[serde(flatten)]
+    comparison: &'a Comparison,
+}
+
+// A classification as returned by the API, with the ID of the image to send feedback on.
+#[derive(Serialize)]
+struct ClassificationResponse {
+    image_id: String,
+    // Proves that the client sent the image, when it gives feedback on it
+    feedback_token: String,
+    // Identifies the result along with the image, e.g. to fetch the copy annotated with it
+    result_id: String,
+    // The type of the image, as detected from its magic bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mime: Option<&'static str>,
+    // Whether the result was served from the cache
+    cached: bool,
+    // The language of the localized labels
+    lang: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explanation: Option<ExplanationResponse>,
+    #[serde(flatten)]
+    classification: Classification,
+}
+
+// An explanation as returned by the API, with the URL of its heatmap.
+#[derive(Serialize)]
+struct ExplanationResponse {
+    heatmap: String,
+    #[serde(flatten)]
+    explanation: Explanation,
+}
+
+// Explain the top class of an image when the request asks for it, and save the heatmap as a copy
+// of the image named by the ID of the result it explains.
+fn explain_image(
+    image: &[u8],
+    result_id: &str,
+    model: &str,
+    explain: &ExplainOptions,
+) -> Result<Option<ExplanationResponse>, InferenceError> {
+    if !explain.explain {
+        return Ok(None);
+    }
+    let explanation = explain::explain(image, Some(model), explain)?;
+    images::save_explanation(image, result_id, &explanation).map_err(InferenceError::Internal)?;
+    Ok(Some(ExplanationResponse {
+        heatmap: format!(
+            "/images/{}/{}",
+            history::image_id(image),
+            Rendition::Explanation(result_id.to_string()).file_name()
+        ),
+        explanation,
+    }))
+}
+
+// Classify the images of a JSON request. A single image gets the same response as a binary
+// request, and an array of images gets a list of results, each a classification or an error.
+fn json_inference(
+    body: &[u8],
+    client: Option<SocketAddr>,
+    pinning: &Pinning,
+    locale: &'static Locale,
+) -> warp::reply::Response {
+    let request: ImageRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => {
+            return json_error(&format!("invalid request: {}", e), StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+    let classify = |payload: &str| -> Result<ClassificationResponse, InferenceError> {
+        let image = payload::decode_payload(payload)?;
+        let image_id = history::image_id(&image.data);
+        let data: warp::hyper::body::Bytes = image.data.into();
+        let Processed {
+            mut classification,
+            cached,
+            result_id,
+        } = process_image(
+            data.clone(),
+            &request.options,
+            &request.explain,
+            client,
+            pinning,
+        )?;
+        locale.localize(&mut classification);
+        let explanation =
+            explain_image(&data, &result_id, &classification.model, &request.explain)?;
+        Ok(ClassificationResponse {
+            image_id,
+            feedback_token: feedback::token(&data),
+            result_id,
+            mime: Some(image.mime),
+            cached,
+            lang: &locale.lang,
+            explanation,
+            classification,
+        })
+    };
+    match &request.image {
+        ImagePayloads::One(payload) => match classify(payload) {
+            Ok(response) => warp::reply::json(&response).into_response(),
+            Err(err) => json_error(&err.to_string(), error_status(&err)).into_response(),
+        },
+        ImagePayloads::Many(payloads) => {
+            let results: Vec<serde_json::Value> = payloads
+                .iter()
+                .map(|payload| match classify(payload) {
+                    Ok(response) => serde_json::to_value(&response)
+                        .unwrap_or_else(|e| serde_json::json!({ "error": e.to_string() })),
+                    Err(err) => serde_json::json!({ "error": err.to_string() }),
+                })
+                .collect();
+            warp::reply::json(&serde_json::json!({ "results": results })).into_response()
+        }
+    }
+}
+
+// Build an HTML error response for the web pages. The message is escaped, since errors may quote
+// the request, e.g. the name of an unknown model.
+fn html_error(heading: &str, message: &dyn fmt::Display) -> warp::reply::Html<String> {
+    warp::reply::html(format!(
+        "<h1>{}: {}</h1>",
+        heading,
+        tera::escape_html(&message.to_string())
+    ))
+}
+
+// Build a JSON error response for the API routes.
+fn json_error(message: &str, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status,
+    )
+}
+
+// Map an inference error to the HTTP status code returned to the client.
+fn error_status(err: &InferenceError) -> StatusCode {
+    match err {
+        InferenceError::InvalidImage(_) => StatusCode::BAD_REQUEST,
+        InferenceError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        InferenceError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        InferenceError::UnknownModel(_) => StatusCode::NOT_FOUND,
+        InferenceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Map a feedback error to the HTTP status code returned to the client.
+fn feedback_error_status(err: &FeedbackError) -> StatusCode {
+    match err {
+        FeedbackError::InvalidFeedback(_) => StatusCode::BAD_REQUEST,
+        FeedbackError::UnknownImage(_) => StatusCode::NOT_FOUND,
+        FeedbackError::InvalidToken => StatusCode::FORBIDDEN,
+        FeedbackError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Map a history error to the HTTP status code returned to the client.
+fn history_error_status(err: &HistoryError) -> StatusCode {
+    match err {
+        HistoryError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+        HistoryError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// A classification of an uploaded image.
+struct Processed {
+    classification: Classification,
+    // Whether the result came from the cache
+    cached: bool,
+    // The ID of the result, which names the copy of the image annotated with it
+    result_id: String,
+}
+
+// Classify an uploaded image, or return its cached result when the same image was already
+// classified the same way. A request that does not pick a model is served by a variant of the
+// rollout, if one is configured. A request asking for an explanation that cannot run is rejected
+// before the image is classified.
+fn process_image(
+    image_data: warp::hyper::body::Bytes,
+    options: &InferenceOptions,
+    explain: &ExplainOptions,
+    client: Option<SocketAddr>,
+    pinning: &Pinning,
+) -> Result<Processed, InferenceError> {
+    let start = Instant::now();
+    let assignment = rollout::assign(options.model.as_deref(), pinning, client, &image_data)?;
+    let options = match &assignment {
+        Some(assignment) => Cow::Owned(InferenceOptions {
+            model: Some(assignment.model.clone()),
+            ..options.clone()
+        }),
+        None => Cow::Borrowed(options),
+    };
+    if explain.explain {
+        explain::validate(options.model.as_deref(), explain)?;
+    }
+    let result = classify_cached(&image_data, &options);
+    if let Some(assignment) = &assignment {
+        let observed = result.as_ref().map(|(c, cached)| (c, *cached));
+        rollout::observe(assignment.variant, observed, start.elapsed(), None);
+    }
+    let (classification, cached) = result?;
+    let result_id = cache::result_id(&options)?;
+
+    // A classification that cannot be recorded is still returned to the client
+    let variant = assignment.as_ref().map(|a| a.variant);
+    if let Err(err) = history::record(
+        &image_data,
+        &classification,
+        start.elapsed(),
+        client,
+        variant,
+        false,
+    ) {
+        eprintln!("Failed to record classification: {}", err);
+    }
+    if let Err(err) = images::save(&image_data, &result_id, &classification) {
+        eprintln!("Failed to save the copies of the image: {}", err);
+    }
+
+    // Only the result of the primary is returned, so the candidate runs from the queue of shadow
+    // runs rather than holding up the response
+    if let Some(candidate) = assignment.and_then(|a| a.shadow) {
+        let run = ShadowRun {
+            options: InferenceOptions {
+                model: Some(candidate),
+                ..options.into_owned()
+            },
+            primary_label: classification.predictions.first().map(|p| p.label.clone()),
+            image_data,
+            client,
+        };
+        if SHADOW_RUNS.try_send(run).is_err() {
+            eprintln!(
+                "Skipped a shadow run, {} are already waiting for the candidate",
+                SHADOW_BACKLOG
+            );
+        }
+    }
+    Ok(Processed {
+        classification,
+        cached,
+        result_id,
+    })
+}
+
+// Classify an image, or return its cached result. Also returns whether the result came from the
+// cache.
+fn classify_cached(
+    image_data: &[u8],
+    options: &InferenceOptions,
+) -> Result<(Classification, bool), InferenceError> {
+    let key = cache::key(image_data, options)?;
+    match cache::get(&key) {
+        Some(classification) => {
+            eprintln!("Serving cached result: {}", key);
+            Ok((classification, true))
+        }
+        None => {
+            let classification = inference::infer_bytes(image_data, options)?;
+            cache::insert(&key, &classification);
+            Ok((classification, false))
+        }
+    }
+}
+
+// Most shadow runs waiting for the candidate. The requests beyond them are not classified in its
+// shadow, so that a slow candidate cannot pile up work.
+const SHADOW_BACKLOG: usize = 16;
+
+// How long the queue of shadow runs waits before each run, so that the responses and the requests
+// ready on the runtime are served before the candidate holds up its thread.
+const SHADOW_PAUSE: Duration = Duration::from_millis(50);
+
+// A classification by the candidate of the rollout, in the shadow of the primary.
+pub struct ShadowRun {
+    image_data: warp::hyper::body::Bytes,
+    options: InferenceOptions,
+    client: Option<SocketAddr>,
+    primary_label: Option<String>,
+}
+
+// Define a lazy-static variable to store the queue of shadow runs. It spawns the task draining the
+// queue, so it is initialized from the runtime at startup.
+lazy_static::lazy_static! {
+    pub static ref SHADOW_RUNS: mpsc::Sender<ShadowRun> = {
+        let (sender, receiver) = mpsc::channel(SHADOW_BACKLOG);
+        tokio::spawn(run_shadows(receiver));
+        sender
+    };
+}
+
+// Run the queued shadow runs one at a time. The runtime has a single thread, and WASI offers no
+// other to run the candidate on, so a run still holds up the server for a full classification,
+// only once the response it shadows has been sent and the pending requests have been served. The
+// shadow_sample option bounds how often that happens.
+async fn run_shadows(mut receiver: mpsc::Receiver<ShadowRun>) {
+    while let Some(run) = receiver.recv().await {
+        tokio::time::sleep(SHADOW_PAUSE).await;
+        shadow_image(run);
+    }
+}
+
+// Classify an image with the candidate of the rollout in the shadow of the primary, counting and
+// recording its result without ever returning it.
+fn shadow_image(
+    ShadowRun {
+        image_data,
+        options,
+        client,
+        primary_label,
+    }: ShadowRun,
+) {
+    let start = Instant::now();
+    let result = classify_cached(&image_data, &options);
+    let observed = result.as_ref().map(|(c, cached)| (c, *cached));
+    rollout::observe(
+        Variant::Candidate,
+        observed,
+        start.elapsed(),
+        primary_label.as_deref(),
+    );
+    let recorded = match result {
+        Ok((classification, _)) => history::record(
+            &image_data,
+            &classification,
+            start.elapsed(),
+            client,
+            Some(Variant::Candidate),
+            true,
+        )
+        .map_err(|e| e.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    if let Err(err) = recorded {
+        eprintln!(
+            "Failed to classify the image in the shadow of the primary: {}",
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send an image to the result page with the given query, and return the error page.
+    async fn error_page(query: &str) -> (StatusCode, String) {
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/inference?{}", query))
+            .body(&include_bytes!("../test/banana.jpg")[..])
+            .reply(&inference())
+            .await;
+        let body = String::from_utf8_lossy(response.body()).into_owned();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn escapes_the_request_in_error_pages() {
+        let (status, body) = error_page("model=%3Cscript%3Ealert(1)%3C/script%3E").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(!body.contains("<script>"));
+        assert!(body.contains("&lt;script&gt;alert(1)&lt;&#x2F;script&gt;"));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_feedback_with_a_bad_request() {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/feedback")
+            .header("content-type", "application/json")
+            .body("{\"image_id\": ")
+            .reply(&api_feedback().recover(handle_api_rejection))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body["error"].is_string());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/feedback")
+            .header("content-type", "text/plain")
+            .body("verdict=up")
+            .reply(&feedback().recover(handle_rejection))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(String::from_utf8_lossy(response.body()).starts_with("<h1>"));
+    }
+
+    #[tokio::test]
+    async fn escapes_unknown_label_maps() {
+        let (status, body) = error_page("label_map=%3Cimg%20src%3Dx%20onerror%3Dalert(1)%3E").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!body.contains("<img"));
+        assert!(body.contains("unknown label map: &lt;img src=x onerror=alert(1)&gt;"));
     }
 }
@@ -0,0 +1,118 @@
+// The upload UI of the index page: classify dropped, pasted, chosen and captured images through
+// the JSON API, and show each result inline next to a preview of its image. The strings it shows
+// are localized by the page, as data attributes of the results element.
+(function () {
+    const zone = document.getElementById("drop-zone");
+    const form = document.getElementById("upload-form");
+    const results = document.getElementById("results");
+    const strings = results.dataset;
+
+    function element(tag, text) {
+        const node = document.createElement(tag);
+        if (text !== undefined) {
+            node.textContent = text;
+        }
+        return node;
+    }
+
+    function showResult(card, result) {
+        const status = card.querySelector("p");
+        status.textContent = result.model + (result.cached ? " (cache)" : "");
+        const verdict = result.open_set && result.open_set.verdict;
+        if (verdict === "unknown" || verdict === "low_confidence") {
+            card.lastChild.appendChild(element("strong",
+                verdict === "unknown" ? strings.unknown : strings.lowConfidence));
+        }
+        const list = element("ol");
+        const top = Math.max(0, ...result.predictions.map(function (p) { return p.probability; }));
+        const bounded = result.predictions.every(function (p) {
+            return p.probability >= 0 && p.probability <= 1;
+        });
+        for (const prediction of result.predictions) {
+            const item = element("li", (prediction.localized_label || prediction.label) + " ("
+                + prediction.probability.toFixed(4) + ")");
+            const track = element("span");
+            track.className = "bar";
+            const bar = element("span");
+            const scale = bounded ? 1 : top;
+            bar.style.width = (scale > 0 ? Math.max(0, prediction.probability / scale) * 100 : 0) + "%";
+            track.appendChild(bar);
+            item.appendChild(track);
+            list.appendChild(item);
+        }
+        card.lastChild.appendChild(list);
+        const link = element("a", strings.annotated);
+        link.href = "/images/" + result.image_id + "/annotated-" + result.result_id + ".png";
+        card.lastChild.appendChild(link);
+    }
+
+    async function classify(file) {
+        if (!file.type.startsWith("image/")) {
+            return;
+        }
+        const card = element("div");
+        card.className = "result";
+        const preview = element("img");
+        preview.src = URL.createObjectURL(file);
+        preview.alt = file.name;
+        const details = element("div");
+        details.appendChild(element("p", file.name + ": " + strings.classifying));
+        card.append(preview, details);
+        results.prepend(card);
+        try {
+            const response = await fetch("/api/v1/inference?lang=" + encodeURIComponent(strings.lang), {
+                method: "POST",
+                headers: { "Content-Type": file.type },
+                body: file,
+            });
+            const result = await response.json();
+            if (!response.ok) {
+                throw new Error(result.error || response.statusText);
+            }
+            showResult(card, result);
+        } catch (error) {
+            details.firstChild.textContent = file.name + ": " + strings.failed + " " + error.message;
+        }
+    }
+
+    function classifyAll(files) {
+        for (const file of files) {
+            classify(file);
+        }
+    }
+
+    // With JavaScript, chosen files are classified as soon as they are picked
+    form.querySelector("input[type=submit]").hidden = true;
+    form.addEventListener("change", function (event) {
+        classifyAll(event.target.files);
+        event.target.value = "";
+    });
+    const camera = document.getElementById("camera");
+    camera.hidden = false;
+    camera.addEventListener("change", function (event) {
+        classifyAll(event.target.files);
+        event.target.value = "";
+    });
+
+    zone.addEventListener("dragover", function (event) {
+        event.preventDefault();
+        zone.classList.add("dragging");
+    });
+    zone.addEventListener("dragleave", function () {
+        zone.classList.remove("dragging");
+    });
+    zone.addEventListener("drop", function (event) {
+        event.preventDefault();
+        zone.classList.remove("dragging");
+        classifyAll(event.dataTransfer.files);
+    });
+    document.addEventListener("paste", function (event) {
+        const files = [];
+        for (const item of (event.clipboardData || {}).items || []) {
+            if (item.kind === "file") {
+                files.push(item.getAsFile());
+            }
+        }
+        classifyAll(files);
+    });
+})();
@@ -0,0 +1,49 @@
+use lazy_static::lazy_static;
+use tera::Tera;
+
+// Define static variables for HTML templates
+static BASE_TEMPLATE: &str = include_str!("templates/base.html");
+static INDEX_TEMPLATE: &str = include_str!("templates/index.html");
+static INFERENCE_TEMPLATE: &str = include_str!("templates/inference.html");
+static NOT_FOUND_TEMPLATE: &str = include_str!("templates/404.html");
+static EVALUATION_TEMPLATE: &str = include_str!("templates/evaluation.html");
+static HISTORY_TEMPLATE: &str = include_str!("templates/history.html");
+static FEEDBACK_TEMPLATE: &str = include_str!("templates/feedback.html");
+static MACROS_TEMPLATE: &str = include_str!("templates/macros.html");
+static COMPARE_TEMPLATE: &str = include_str!("templates/compare.html");
+
+// Define a lazy-static variable to store the Tera instance
+lazy_static! {
+    static ref TERA: Tera = {
+        let mut tera = Tera::default();
+
+        // Add the templates to the Tera instance
+        tera.add_raw_template("base.html", BASE_TEMPLATE).unwrap();
+        // Macros are imported by the templates below, so they must be added first
+        tera.add_raw_template("macros.html", MACROS_TEMPLATE).unwrap();
+        tera.add_raw_template("index.html", INDEX_TEMPLATE).unwrap();
+        tera.add_raw_template("inference.html", INFERENCE_TEMPLATE)
+            .unwrap();
+        tera.add_raw_template("404.html", NOT_FOUND_TEMPLATE)
+            .unwrap();
+        tera.add_raw_template("evaluation.html", EVALUATION_TEMPLATE)
+            .unwrap();
+        tera.add_raw_template("history.html", HISTORY_TEMPLATE)
+            .unwrap();
+        tera.add_raw_template("feedback.html", FEEDBACK_TEMPLATE)
+            .unwrap();
+        tera.add_raw_template("compare.html", COMPARE_TEMPLATE)
+            .unwrap();
+        tera
+    };
+}
+
+pub fn render_template_context(
+    template_name: &str,
+    context: &tera::Context,
+) -> Result<String, String> {
+    match TERA.render(template_name, context) {
+        Ok(rendered) => Ok(rendered),
+        Err(e) => Err(format!("Error rendering template: {}", e)),
+    }
+}
@@ -0,0 +1,370 @@
+// Raw tensors, for callers that do their own preprocessing: decoded from and encoded to JSON with
+// base64 data, a bare binary body, or a NumPy .npy file, and run through a model as-is.
+use crate::inference::{self, InferenceError, Model, Served, REGISTRY};
+use crate::oip::OUTPUT_NAME;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+// The only element type models take and return
+pub const DTYPE: &str = "float32";
+
+// The magic string at the start of every .npy file
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TensorFormat {
+    // {"shape": [...], "dtype": "float32", "data": "<base64>"}
+    Json,
+    // Little-endian values, with the shape and type in the query or in response headers
+    Binary,
+    // A NumPy .npy file
+    Npy,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TensorOptions {
+    // Name of the model, the default one when not set
+    pub model: Option<String>,
+    // Shape of a binary input, e.g. "1,3,224,224"
+    pub shape: Option<String>,
+    // Element type of a binary input, float32 when not set
+    pub dtype: Option<String>,
+    // Encoding of the output, the same as the input when not set
+    pub format: Option<TensorFormat>,
+}
+
+// A tensor of float32 values in row-major order.
+#[derive(Debug, Clone)]
+pub struct Tensor {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+// A tensor as it is sent in JSON.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JsonTensor {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub shape: Vec<usize>,
+    pub dtype: String,
+    // The little-endian values, base64 encoded
+    pub data: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonOutputs {
+    pub model: String,
+    pub outputs: Vec<JsonTensor>,
+}
+
+// Decode a tensor from a request body: a .npy file, recognized by its magic string, JSON when the
+// content type says so, and otherwise binary values with the shape and type in the options.
+pub fn decode(
+    body: &[u8],
+    content_type: Option<&str>,
+    options: &TensorOptions,
+) -> Result<(Tensor, TensorFormat), InferenceError> {
+    if body.starts_with(NPY_MAGIC) {
+        return Ok((decode_npy(body)?, TensorFormat::Npy));
+    }
+    if content_type.is_some_and(|t| t.starts_with("application/json")) {
+        let tensor: JsonTensor = serde_json::from_slice(body)
+            .map_err(|e| InferenceError::InvalidInput(format!("invalid tensor: {}", e)))?;
+        check_dtype(&tensor.dtype)?;
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(&tensor.data)
+            .map_err(|e| InferenceError::InvalidInput(format!("invalid base64: {}", e)))?;
+        return Ok((from_le_bytes(tensor.shape, &data)?, TensorFormat::Json));
+    }
+    let Some(shape) = &options.shape else {
+        return Err(invalid_input(
+            "a binary tensor needs its shape in the shape parameter",
+        ));
+    };
+    let shape = shape
+        .split(',')
+        .map(|d| d.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| InferenceError::InvalidInput(format!("invalid shape: {}", shape)))?;
+    check_dtype(options.dtype.as_deref().unwrap_or(DTYPE))?;
+    Ok((from_le_bytes(shape, body)?, TensorFormat::Binary))
+}
+
+// Run a tensor through a model as-is, after checking it against the input signature of the model,
+// and return the output tensor.
+pub fn run(
+    model_name: Option<&str>,
+    input: &Tensor,
+) -> Result<(&'static Model, Tensor), InferenceError> {
+    let model = match REGISTRY.resolve(model_name)? {
+        Served::Model(model) => model,
+        Served::Ensemble(ensemble) => {
+            return Err(InferenceError::InvalidInput(format!(
+                "{} is an ensemble, raw tensors can only be run through a model",
+                ensemble.name
+            )))
+        }
+    };
+    let batch = inference::check_input_shape(model, &input.shape)?;
+    let output = Tensor {
+        shape: vec![batch, model.classes],
+        data: inference::run_batch(model, &input.data, batch)?,
+    };
+    Ok((model, output))
+}
+
+// Encode an output tensor as a JSON document holding the outputs of a model.
+pub fn encode_json(model: &Model, output: &Tensor) -> JsonOutputs {
+    JsonOutputs {
+        model: model.name.clone(),
+        outputs: vec![JsonTensor {
+            name: Some(OUTPUT_NAME.to_string()),
+            shape: output.shape.clone(),
+            dtype: DTYPE.to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(to_le_bytes(&output.data)),
+        }],
+    }
+}
+
+// Encode a tensor as little-endian binary values.
+pub fn encode_binary(tensor: &Tensor) -> Vec<u8> {
+    to_le_bytes(&tensor.data)
+}
+
+// Encode a tensor as a version 1.0 .npy file, whose header is padded so that the data is aligned
+// on 64 bytes.
+pub fn encode_npy(tensor: &Tensor) -> Vec<u8> {
+    let dimensions: Vec<String> = tensor.shape.iter().map(|d| d.to_string()).collect();
+    // A tuple of one element needs a trailing comma
+    let shape = match dimensions.len() {
+        1 => format!("({},)", dimensions[0]),
+        _ => format!("({})", dimensions.join(", ")),
+    };
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    let unpadded = NPY_MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+
+    let mut npy = NPY_MAGIC.to_vec();
+    npy.extend_from_slice(&[1, 0]);
+    npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    npy.extend_from_slice(header.as_bytes());
+    npy.extend_from_slice(&to_le_bytes(&tensor.data));
+    npy
+}
+
+// Format the shape of a tensor for the response headers of a binary tensor, e.g. "1,1000".
+pub fn shape_header(tensor: &Tensor) -> String {
+    let dimensions: Vec<String> = tensor.shape.iter().map(|d| d.to_string()).collect();
+    dimensions.join(",")
+}
+
+fn invalid_input(message: &str) -> InferenceError {
+    InferenceError::InvalidInput(message.to_string())
+}
+
+fn check_dtype(dtype: &str) -> Result<(), InferenceError> {
+    match dtype {
+        DTYPE => Ok(()),
+        _ => Err(InferenceError::InvalidInput(format!(
+            "unsupported dtype {}, models take {}",
+            dtype, DTYPE
+        ))),
+    }
+}
+
+// Read the little-endian values of a tensor of the given shape, which must all be finite.
+fn from_le_bytes(shape: Vec<usize>, data: &[u8]) -> Result<Tensor, InferenceError> {
+    let expected = shape
+        .iter()
+        .try_fold(4usize, |bytes, dimension| bytes.checked_mul(*dimension))
+        .ok_or_else(|| {
+            InferenceError::InvalidInput(format!("the tensor shape {:?} is too large", shape))
+        })?;
+    if data.len() != expected {
+        return Err(InferenceError::InvalidInput(format!(
+            "a {} tensor of shape {:?} takes {} bytes, got {}",
+            DTYPE,
+            shape,
+            expected,
+            data.len()
+        )));
+    }
+    let data: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    inference::check_finite(&data)?;
+    Ok(Tensor { shape, data })
+}
+
+fn to_le_bytes(data: &[f32]) -> Vec<u8> {
+    data.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+// Decode a .npy file of little-endian float32 values in C order. The header is a Python dict
+// literal such as {'descr': '<f4', 'fortran_order': False, 'shape': (1, 3, 224, 224), }.
+fn decode_npy(npy: &[u8]) -> Result<Tensor, InferenceError> {
+    let truncated = || invalid_input("truncated .npy file");
+    let version = *npy.get(NPY_MAGIC.len()).ok_or_else(truncated)?;
+    // Version 1 has a 2-byte header length, later versions a 4-byte one
+    let (length, start): (usize, usize) = match version {
+        1 => {
+            let bytes = npy.get(8..10).ok_or_else(truncated)?;
+            (u16::from_le_bytes([bytes[0], bytes[1]]) as usize, 10)
+        }
+        2 | 3 => {
+            let bytes = npy.get(8..12).ok_or_else(truncated)?;
+            (
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize,
+                12,
+            )
+        }
+        _ => {
+            return Err(InferenceError::InvalidInput(format!(
+                "unsupported .npy version {}",
+                version
+            )))
+        }
+    };
+    let end = start.checked_add(length).ok_or_else(truncated)?;
+    let header = npy.get(start..end).ok_or_else(truncated)?;
+    let header = String::from_utf8_lossy(header);
+
+    let descr = header_value(&header, "descr")
+        .and_then(|v| v.strip_prefix('\''))
+        .and_then(|v| v.split('\'').next())
+        .ok_or_else(|| invalid_input(".npy header has no descr"))?;
+    if descr != "<f4" {
+        return Err(InferenceError::InvalidInput(format!(
+            "unsupported .npy dtype {}, models take little-endian {} ('<f4')",
+            descr, DTYPE
+        )));
+    }
+    if header_value(&header, "fortran_order").is_some_and(|v| v.starts_with("True")) {
+        return Err(invalid_input(
+            ".npy arrays in Fortran order are not supported",
+        ));
+    }
+    let shape = header_value(&header, "shape")
+        .and_then(|v| v.strip_prefix('('))
+        .and_then(|v| v.split(')').next())
+        .ok_or_else(|| invalid_input(".npy header has no shape"))?;
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| InferenceError::InvalidInput(format!("invalid .npy shape: ({})", shape)))?;
+    from_le_bytes(shape, &npy[end..])
+}
+
+// The text following a key of the header dict of a .npy file.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let position = header.find(&format!("'{}':", key))?;
+    Some(header[position + key.len() + 3..].trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A .npy file of the given version, header and data.
+    fn npy(version: u8, header: &str, data: &[f32]) -> Vec<u8> {
+        let mut npy = NPY_MAGIC.to_vec();
+        npy.extend_from_slice(&[version, 0]);
+        match version {
+            1 => npy.extend_from_slice(&(header.len() as u16).to_le_bytes()),
+            _ => npy.extend_from_slice(&(header.len() as u32).to_le_bytes()),
+        }
+        npy.extend_from_slice(header.as_bytes());
+        npy.extend_from_slice(&to_le_bytes(data));
+        npy
+    }
+
+    fn error(result: Result<Tensor, InferenceError>) -> String {
+        match result {
+            Err(InferenceError::InvalidInput(message)) => message,
+            other => panic!("expected invalid input, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_npy_files() {
+        let tensor = Tensor {
+            shape: vec![2, 3],
+            data: vec![0.5, -1.0, 2.0, 3.25, 0.0, 1e-3],
+        };
+        let encoded = encode_npy(&tensor);
+        // The data starts on 64 bytes
+        assert_eq!((encoded.len() - 24) % 64, 0);
+        let decoded = decode_npy(&encoded).unwrap();
+        assert_eq!(decoded.shape, tensor.shape);
+        assert_eq!(decoded.data, tensor.data);
+
+        let vector = Tensor {
+            shape: vec![3],
+            data: vec![1.0, 2.0, 3.0],
+        };
+        let encoded = encode_npy(&vector);
+        assert!(String::from_utf8_lossy(&encoded).contains("'shape': (3,)"));
+        assert_eq!(decode_npy(&encoded).unwrap().shape, vec![3]);
+    }
+
+    #[test]
+    fn reads_version_2_headers() {
+        let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (1, 2), }\n";
+        let tensor = decode_npy(&npy(2, header, &[1.0, 2.0])).unwrap();
+        assert_eq!(tensor.shape, vec![1, 2]);
+        assert_eq!(tensor.data, vec![1.0, 2.0]);
+        let message = error(decode_npy(&npy(4, header, &[1.0, 2.0])));
+        assert!(message.contains("version 4"));
+    }
+
+    #[test]
+    fn rejects_truncated_npy_files() {
+        let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (2,), }\n";
+        let npy = npy(1, header, &[1.0, 2.0]);
+        for length in [NPY_MAGIC.len(), 9, 10 + header.len() / 2] {
+            assert_eq!(error(decode_npy(&npy[..length])), "truncated .npy file");
+        }
+        let message = error(decode_npy(&npy[..npy.len() - 1]));
+        assert!(message.contains("takes 8 bytes, got 7"));
+    }
+
+    #[test]
+    fn takes_only_little_endian_floats_in_c_order() {
+        let little = "{'descr': '<f4', 'fortran_order': False, 'shape': (1,), }";
+        assert!(decode_npy(&npy(1, little, &[1.0])).is_ok());
+        let big = "{'descr': '>f4', 'fortran_order': False, 'shape': (1,), }";
+        assert!(error(decode_npy(&npy(1, big, &[1.0]))).contains("unsupported .npy dtype >f4"));
+        let fortran = "{'descr': '<f4', 'fortran_order': True, 'shape': (1, 1), }";
+        assert!(error(decode_npy(&npy(1, fortran, &[1.0]))).contains("Fortran order"));
+    }
+
+    #[test]
+    fn rejects_shapes_that_overflow() {
+        let header = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, 2), }}",
+            usize::MAX
+        );
+        assert!(error(decode_npy(&npy(1, &header, &[]))).contains("too large"));
+        assert!(error(from_le_bytes(vec![usize::MAX / 2, 2], &[])).contains("too large"));
+    }
+
+    #[test]
+    fn reads_little_endian_values() {
+        let bytes = to_le_bytes(&[1.5, -2.0]);
+        assert_eq!(bytes[..4], 1.5f32.to_le_bytes());
+        let tensor = from_le_bytes(vec![2, 1], &bytes).unwrap();
+        assert_eq!(tensor.data, vec![1.5, -2.0]);
+        assert!(error(from_le_bytes(vec![3], &bytes)).contains("takes 12 bytes, got 8"));
+        let nan = to_le_bytes(&[f32::NAN]);
+        assert!(error(from_le_bytes(vec![1], &nan)).contains("non-finite"));
+    }
+}